@@ -0,0 +1,7 @@
+-- Add migration script here
+alter table coupon add column if not exists "reservation_id" uuid;
+alter table coupon add column if not exists "reserved_until" timestamptz;
+
+create unique index if not exists coupon_reservation_id_idx on coupon ("reservation_id");
+create index if not exists coupon_reserved_until_idx on coupon ("reserved_until")
+    where "reserved_until" is not null;
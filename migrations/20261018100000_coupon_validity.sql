@@ -0,0 +1,8 @@
+-- Add migration script here
+alter table coupon_set add column if not exists "starts_at" timestamptz;
+alter table coupon_set add column if not exists "ends_at" timestamptz;
+
+alter table coupon add column if not exists "expires_at" timestamptz;
+
+create index if not exists coupon_expires_at_idx on coupon ("expires_at")
+    where "expires_at" is not null;
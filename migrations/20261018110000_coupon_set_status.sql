@@ -0,0 +1,6 @@
+-- Add migration script here
+alter table coupon_set add column if not exists "status" varchar not null default 'active';
+
+alter table coupon_set drop constraint if exists coupon_set_status_check;
+alter table coupon_set add constraint coupon_set_status_check
+    check ("status" in ('active', 'paused', 'archived'));
@@ -0,0 +1,17 @@
+-- Add migration script here
+create table if not exists upload_job (
+    "id" uuid default gen_random_uuid (),
+    "set_id" bigint not null,
+    "state" varchar not null default 'queued',
+    "parsed" bigint not null default 0,
+    "rejected" bigint not null default 0,
+    "inserted" bigint not null default 0,
+    "error" varchar,
+    "created_at" timestamptz not null default now (),
+    "updated_at" timestamptz not null default now (),
+    primary key ("id"),
+    constraint fk_upload_job_coupon_set foreign key ("set_id") references coupon_set ("id")
+        on delete cascade,
+    constraint upload_job_state_check
+        check ("state" in ('queued', 'running', 'succeeded', 'failed'))
+);
@@ -0,0 +1,17 @@
+-- Add migration script here
+alter table coupon add column if not exists "upload_job_id" uuid;
+
+create table if not exists upload_rejection (
+    "id" bigserial,
+    "job_id" uuid not null,
+    "code" varchar not null,
+    "reason" varchar not null,
+    "existing_set_id" bigint,
+    primary key ("id"),
+    constraint fk_upload_rejection_upload_job foreign key ("job_id") references upload_job ("id")
+        on delete cascade,
+    constraint upload_rejection_reason_check
+        check ("reason" in ('invalid', 'duplicate', 'existing'))
+);
+
+create index if not exists upload_rejection_job_id_idx on upload_rejection ("job_id");
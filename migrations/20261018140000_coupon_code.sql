@@ -0,0 +1,12 @@
+-- Add migration script here
+alter table coupon_set add column if not exists "code_format" jsonb not null
+    default '{"type": "uuid"}';
+
+alter table coupon add column if not exists "code" varchar;
+
+-- Coupons so far were UUIDs only, their code is the id
+update coupon set "code" = "id"::text where "code" is null;
+
+alter table coupon alter column "code" set not null;
+
+create unique index if not exists coupon_code_idx on coupon ("code");
@@ -0,0 +1,6 @@
+-- Add migration script here
+alter table upload_job add column if not exists "kind" varchar not null default 'upload';
+
+alter table upload_job drop constraint if exists upload_job_kind_check;
+alter table upload_job add constraint upload_job_kind_check
+    check ("kind" in ('upload', 'generate'));
@@ -0,0 +1,3 @@
+-- Add migration script here
+alter table coupon add column if not exists "created_at" timestamptz not null default now ();
+alter table coupon add column if not exists "issued_at" timestamptz;
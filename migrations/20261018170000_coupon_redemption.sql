@@ -0,0 +1,6 @@
+-- Add migration script here
+alter table coupon add column if not exists "redeemed_at" timestamptz;
+alter table coupon add column if not exists "redemption_key" varchar;
+
+create index if not exists coupon_set_id_issued_at_idx on coupon ("set_id")
+    where "issued_at" is not null;
@@ -0,0 +1,18 @@
+-- Add migration script here
+-- Coupons that were handed out, kept for audits. No foreign key on the set so that the history
+-- outlives deleted sets.
+create table if not exists coupon_used (
+    "code" varchar not null,
+    "set_id" bigint not null,
+    "upload_job_id" uuid,
+    "created_at" timestamptz not null,
+    "expires_at" timestamptz,
+    "issued_at" timestamptz not null,
+    "issued_to" varchar,
+    "redeemed_at" timestamptz,
+    "redemption_key" varchar,
+    primary key ("code")
+);
+
+create index if not exists coupon_used_set_id_idx on coupon_used ("set_id");
+create index if not exists coupon_used_issued_at_idx on coupon_used ("issued_at");
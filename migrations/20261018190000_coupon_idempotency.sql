@@ -0,0 +1,12 @@
+-- Add migration script here
+-- Idempotency keys of the postgres coupon store, the redis store keeps them in the cache. The code
+-- is null while the pop is in progress.
+create table if not exists coupon_idempotency (
+    "set_id" bigint not null,
+    "key" varchar not null,
+    "code" varchar,
+    "expires_at" timestamptz not null,
+    primary key ("set_id", "key")
+);
+
+create index if not exists coupon_idempotency_expires_at_idx on coupon_idempotency ("expires_at");
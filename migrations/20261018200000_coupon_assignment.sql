@@ -0,0 +1,12 @@
+-- Add migration script here
+-- Sticky sets hand out a single coupon per subject, pops from the same subject return it again.
+-- The code is null while the first pop is in progress.
+alter table coupon_set add column if not exists "sticky" boolean not null default false;
+
+create table if not exists coupon_assignment (
+    "set_id" bigint not null references coupon_set ("id") on delete cascade,
+    "subject" varchar not null,
+    "code" varchar,
+    "assigned_at" timestamptz not null default now(),
+    primary key ("set_id", "subject")
+);
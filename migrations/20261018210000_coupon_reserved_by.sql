@@ -0,0 +1,3 @@
+-- Add migration script here
+-- Subject that made the reservation, only they can confirm or cancel it
+alter table coupon add column if not exists "reserved_by" varchar;
@@ -0,0 +1,9 @@
+-- Add migration script here
+-- Who cancelled a reservation and who redeemed a coupon, the archive keeps them along with who
+-- reserved and was issued the coupon
+alter table coupon add column if not exists "cancelled_by" varchar;
+alter table coupon add column if not exists "redeemed_by" varchar;
+
+alter table coupon_used add column if not exists "reserved_by" varchar;
+alter table coupon_used add column if not exists "cancelled_by" varchar;
+alter table coupon_used add column if not exists "redeemed_by" varchar;
@@ -0,0 +1,10 @@
+-- Add migration script here
+-- Idempotency keys are scoped to the subject that sent them. Keys from before can't be tied to a
+-- subject, they are dropped instead of being replayed to whoever reuses them.
+delete from coupon_idempotency;
+
+alter table coupon_idempotency add column if not exists "subject" varchar not null;
+
+alter table coupon_idempotency drop constraint if exists coupon_idempotency_pkey;
+alter table coupon_idempotency add constraint coupon_idempotency_pkey
+    primary key ("set_id", "subject", "key");
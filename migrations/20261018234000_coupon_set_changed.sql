@@ -0,0 +1,13 @@
+-- Add migration script here
+-- Instances cache coupon sets in memory, status changes and deletes are broadcast so that every
+-- instance drops its copy right away
+create or replace function notify_coupon_set_changed() returns trigger as $$
+begin
+    perform pg_notify('coupon_set_changed', old."id"::text);
+    return null;
+end;
+$$ language plpgsql;
+
+drop trigger if exists coupon_set_changed on coupon_set;
+create trigger coupon_set_changed after update of "status" or delete on coupon_set
+    for each row execute function notify_coupon_set_changed();
@@ -7,6 +7,7 @@ set -e -u
 REALM_NAME=${KC_SETUP_REALM}
 USER_NAME=${KC_SETUP_USER_NAME}
 USER_PASSWORD=${KC_SETUP_USER_PASSWORD}
+CLIENT_ID=${KC_SETUP_CLIENT_ID}
 
 { echo "Waiting for Keycloak Server"; } 2> /dev/null
 
@@ -33,6 +34,14 @@ curl -s --request POST \
   --header 'content-type: application/json' \
   --data "{\"realm\": \"${REALM_NAME}\", \"enabled\": \"true\"}"
 
+{ echo "Creating Client \"${CLIENT_ID}\""; } 2> /dev/null
+
+curl -s --request POST \
+  --url http://localhost:8080/admin/realms/${REALM_NAME}/clients \
+  --header "authorization: bearer ${ACCESS_TOKEN}" \
+  --header 'content-type: application/json' \
+  --data "{\"clientId\": \"${CLIENT_ID}\", \"enabled\": true, \"publicClient\": true, \"directAccessGrantsEnabled\": true, \"standardFlowEnabled\": false}"
+
 { echo "Creating User \"${USER_NAME}\""; } 2> /dev/null
 
 curl -s --request POST \
@@ -42,4 +51,33 @@ curl -s --request POST \
   --header 'user-agent: vscode-restclient' \
   --data "{\"firstName\": \"S2S User\", \"lastName\": \"Auto Generated\",\"username\": \"${USER_NAME}\",\"email\": \"thestack@mail.com\", \"emailVerified\": \"true\",\"enabled\": \"true\",\"credentials\": [{\"type\": \"password\",\"value\": \"${USER_PASSWORD}\",\"temporary\": \"false\"}]}"
 
+{ echo "Creating roles \"${AUTH_ADMIN_ROLE}\" and \"${AUTH_CONSUMER_ROLE}\""; } 2> /dev/null
+
+for ROLE_NAME in ${AUTH_ADMIN_ROLE} ${AUTH_CONSUMER_ROLE}; do
+  curl -s --request POST \
+    --url http://localhost:8080/admin/realms/${REALM_NAME}/roles \
+    --header "authorization: bearer ${ACCESS_TOKEN}" \
+    --header 'content-type: application/json' \
+    --data "{\"name\": \"${ROLE_NAME}\"}"
+done
+
+{ echo "Granting roles to \"${USER_NAME}\""; } 2> /dev/null
+
+USER_ID=$(curl -s --request GET \
+  --url "http://localhost:8080/admin/realms/${REALM_NAME}/users?username=${USER_NAME}&exact=true" \
+  --header "authorization: bearer ${ACCESS_TOKEN}" \
+  | jq -r ".[0].id")
+
+ROLES=$(for ROLE_NAME in ${AUTH_ADMIN_ROLE} ${AUTH_CONSUMER_ROLE}; do
+  curl -s --request GET \
+    --url http://localhost:8080/admin/realms/${REALM_NAME}/roles/${ROLE_NAME} \
+    --header "authorization: bearer ${ACCESS_TOKEN}"
+done | jq -s ".")
+
+curl -s --request POST \
+  --url http://localhost:8080/admin/realms/${REALM_NAME}/users/${USER_ID}/role-mappings/realm \
+  --header "authorization: bearer ${ACCESS_TOKEN}" \
+  --header 'content-type: application/json' \
+  --data "${ROLES}"
+
 { echo "Keycloak setup done"; } 2> /dev/null
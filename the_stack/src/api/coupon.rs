@@ -1,84 +1,579 @@
 use std::sync::Arc;
 
+use anyhow::anyhow;
+use axum::body::Body;
+use axum::extract::DefaultBodyLimit;
+use axum::extract::FromRequest;
+use axum::extract::Multipart;
 use axum::extract::Path;
+use axum::extract::Query;
+use axum::extract::Request;
 use axum::extract::State;
+use axum::http::header::ACCEPT;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderMap;
+use axum::middleware;
+use axum::response::IntoResponse;
+use axum::response::Response;
 use axum::Json;
 use axum::Router;
+use futures::StreamExt;
+use uuid::Uuid;
 
+use crate::api::dto::ClaimCouponsDto;
+use crate::api::dto::CouponLookupResponseDto;
 use crate::api::dto::CouponStatusResponseDto;
 use crate::api::dto::CreateCouponSetDto;
+use crate::api::dto::GenerateCouponsDto;
+use crate::api::dto::UpdateCouponSetDto;
+use crate::api::dto::UploadCouponsQuery;
+use crate::api::dto::UploadReportResponseDto;
+use crate::api::upload;
+use crate::api::upload::UploadFormat;
 use crate::api::AppState;
-use crate::cache::coupon::CouponCache;
+use crate::auth::role::RoleGuard;
+use crate::auth::Principal;
 use crate::database::coupon::CouponRepository;
+use crate::database::upload::UploadJobRepository;
 use crate::error::api::ApiError;
 use crate::error::api::ApiResult;
 use crate::model::coupon::Coupon;
+use crate::model::coupon::CouponReservation;
 use crate::model::coupon::CouponSet;
+use crate::model::coupon::MAX_CODE_LENGTH;
+use crate::model::upload::UploadJob;
 use crate::service::coupon::CouponService;
+use crate::service::coupon::UploadSender;
+use crate::service::ClaimConfig;
+use crate::service::GenerateConfig;
+
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+const IDEMPOTENCY_KEY_MAX_LENGTH: usize = 255;
+const CLAIM_ERROR_HEADER: &str = "claim-error";
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+const JSON_CONTENT_TYPE: &str = "application/json";
+const MULTIPART_CONTENT_TYPE: &str = "multipart/form-data";
+const JSON_UPLOAD_MAX_BYTES: usize = 2 * 1024 * 1024;
+
+enum UploadSource {
+    Json(Vec<String>),
+    Stream(UploadFormat, Request),
+    Multipart(Request),
+}
 
 struct CouponAppState {
     service: CouponService,
+    claim_config: ClaimConfig,
+    generate_config: GenerateConfig,
 }
 
 pub fn router(ctx: AppState) -> Router {
+    let admin =
+        middleware::from_fn_with_state(RoleGuard::new(&ctx.role_config.admin), RoleGuard::require);
+    let consumer = middleware::from_fn_with_state(
+        RoleGuard::new(&ctx.role_config.consumer),
+        RoleGuard::require,
+    );
+
     Router::<Arc<CouponAppState>>::new()
-        .route("/coupon_set/:set_id/coupon", axum::routing::get(pop_coupon))
+        .route(
+            "/coupon_set/:set_id/coupon",
+            axum::routing::get(pop_coupon).route_layer(consumer.clone()),
+        )
         .route(
             "/coupon_set/:set_id/upload",
-            axum::routing::post(upload_coupons),
+            // Streamed uploads are not buffered, so they are not bound by the default body limit
+            axum::routing::post(upload_coupons)
+                .layer(DefaultBodyLimit::disable())
+                .route_layer(admin.clone()),
+        )
+        .route(
+            "/coupon_set/:set_id/generate",
+            axum::routing::post(generate_coupons).route_layer(admin.clone()),
+        )
+        .route(
+            "/coupon_set/:set_id/coupons/claim",
+            axum::routing::post(claim_coupons).route_layer(consumer.clone()),
+        )
+        .route(
+            "/coupon_set/:set_id/reservation",
+            axum::routing::post(reserve_coupon).route_layer(consumer.clone()),
+        )
+        .route(
+            "/reservation/:reservation_id/confirm",
+            axum::routing::post(confirm_reservation).route_layer(consumer.clone()),
+        )
+        .route(
+            "/reservation/:reservation_id/cancel",
+            axum::routing::post(cancel_reservation).route_layer(consumer.clone()),
+        )
+        // Lookups are open to every authenticated caller
+        .route("/coupon/:code", axum::routing::get(lookup_coupon))
+        .route(
+            "/coupon/:code/redeem",
+            axum::routing::post(redeem_coupon).route_layer(consumer),
+        )
+        .route(
+            "/upload_job/:job_id",
+            axum::routing::get(get_upload_job).route_layer(admin.clone()),
+        )
+        .route(
+            "/upload_job/:job_id/report",
+            axum::routing::get(get_upload_report).route_layer(admin.clone()),
+        )
+        .route(
+            "/coupon_set",
+            axum::routing::post(create_set).route_layer(admin.clone()),
+        )
+        .route(
+            "/coupon_set/status",
+            axum::routing::get(set_status).route_layer(admin.clone()),
+        )
+        .route(
+            "/coupon_set/:set_id",
+            axum::routing::patch(update_set)
+                .delete(delete_set)
+                .route_layer(admin),
         )
-        .route("/coupon_set", axum::routing::post(create_set))
-        .route("/coupon_set/status", axum::routing::get(set_status))
         .with_state(
             (CouponAppState {
                 service: CouponService::new(
-                    CouponRepository::new(ctx.db),
-                    CouponCache::new(ctx.cache),
+                    CouponRepository::new(ctx.db.clone()),
+                    UploadJobRepository::new(ctx.db),
+                    ctx.store,
                     ctx.metrics.clone(),
-                    ctx.lock,
-                    ctx.batch_config,
+                    ctx.coupon_config,
                 ),
+                claim_config: ctx.claim_config,
+                generate_config: ctx.generate_config,
             })
             .into(),
         )
 }
 
-#[tracing::instrument(skip_all)]
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
 async fn pop_coupon(
     State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
+    Path(set_id): Path<i64>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Coupon>> {
+    let Some(idempotency_key) = idempotency_key(&headers)? else {
+        let value = ctx.service.pop_coupon(set_id, &principal).await?;
+        return Ok(Json(value));
+    };
+
+    let value = ctx
+        .service
+        .pop_coupon_idempotent(set_id, &idempotency_key, &principal)
+        .await?;
+    Ok(Json(value))
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
+async fn claim_coupons(
+    State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
     Path(set_id): Path<i64>,
+    headers: HeaderMap,
+    Json(claim_dto): Json<ClaimCouponsDto>,
+) -> ApiResult<Response> {
+    if claim_dto.count < 1 || claim_dto.count > ctx.claim_config.max_count {
+        return Err(ApiError::BadRequest(format!(
+            "Count must be between 1 and {}",
+            ctx.claim_config.max_count
+        )));
+    }
+
+    let ndjson = headers
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains(NDJSON_CONTENT_TYPE));
+
+    let chunks = ctx
+        .service
+        .claim_coupons(set_id, claim_dto.count, principal);
+
+    if !ndjson {
+        let mut chunks = std::pin::pin!(chunks);
+        let mut coupons = vec![];
+
+        while let Some(chunk) = chunks.next().await {
+            match chunk {
+                Ok(chunk) => coupons.extend(chunk),
+                // The earlier chunks are already handed out, so they are returned along with the
+                // status the error would have had
+                Err(err) if !coupons.is_empty() => {
+                    let status = ApiError::from(err).into_response().status();
+
+                    return Ok(
+                        ([(CLAIM_ERROR_HEADER, status.to_string())], Json(coupons)).into_response()
+                    );
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        if coupons.is_empty() {
+            return Err(ApiError::NotFound("No coupons left".to_string()));
+        }
+
+        return Ok(Json(coupons).into_response());
+    }
+
+    // Once streaming starts the status can't be changed anymore, so errors end the body early
+    let lines = chunks.map(|chunk| {
+        let chunk = chunk.map_err(|err| anyhow!("{}", err))?;
+        let mut lines = String::new();
+
+        for coupon in chunk.iter() {
+            lines.push_str(&serde_json::to_string(coupon)?);
+            lines.push('\n');
+        }
+
+        Ok::<String, anyhow::Error>(lines)
+    });
+
+    Ok((
+        [(CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+        Body::from_stream(lines),
+    )
+        .into_response())
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
+async fn reserve_coupon(
+    State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
+    Path(set_id): Path<i64>,
+) -> ApiResult<Json<CouponReservation>> {
+    let value = ctx.service.reserve_coupon(set_id, &principal).await?;
+    Ok(Json(value))
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
+async fn confirm_reservation(
+    State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
+    Path(reservation_id): Path<Uuid>,
 ) -> ApiResult<Json<Coupon>> {
-    let value = ctx.service.pop_coupon(set_id).await?;
+    let value = ctx
+        .service
+        .confirm_reservation(reservation_id, &principal)
+        .await?;
     Ok(Json(value))
 }
 
-#[tracing::instrument(skip_all)]
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
+async fn cancel_reservation(
+    State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
+    Path(reservation_id): Path<Uuid>,
+) -> ApiResult<Json<Coupon>> {
+    let value = ctx
+        .service
+        .cancel_reservation(reservation_id, &principal)
+        .await?;
+    Ok(Json(value))
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
 async fn upload_coupons(
     State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
     Path(set_id): Path<i64>,
-    Json(coupons): Json<Vec<String>>,
-) -> ApiResult<()> {
-    if coupons.is_empty() {
-        return Err(ApiError::BadRequest("Empty coupon list".to_string()));
+    Query(query): Query<UploadCouponsQuery>,
+    headers: HeaderMap,
+    request: Request,
+) -> ApiResult<Json<UploadJob>> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let chunk_size = ctx.service.upload_chunk_size();
+
+    let source = if content_type.starts_with(JSON_CONTENT_TYPE) {
+        // The JSON array can't be streamed, so it keeps the default body limit
+        let body = axum::body::to_bytes(request.into_body(), JSON_UPLOAD_MAX_BYTES)
+            .await
+            .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+
+        let coupons = serde_json::from_slice::<Vec<String>>(&body)
+            .map_err(|err| ApiError::BadRequest(err.to_string()))?;
+
+        if coupons.is_empty() {
+            return Err(ApiError::BadRequest("Empty coupon list".to_string()));
+        }
+
+        UploadSource::Json(coupons)
+    } else if content_type.starts_with(MULTIPART_CONTENT_TYPE) {
+        UploadSource::Multipart(request)
+    } else if let Some(format) = UploadFormat::from_content_type(&content_type) {
+        UploadSource::Stream(format, request)
+    } else {
+        return Err(ApiError::BadRequest(format!(
+            "Unsupported content type {}",
+            content_type
+        )));
+    };
+
+    let (job, sender) = ctx
+        .service
+        .spawn_upload_job(set_id, query.expires_at)
+        .await?;
+
+    let result = match source {
+        UploadSource::Json(coupons) => {
+            let mut result = Ok(());
+
+            for chunk in coupons.chunks(chunk_size) {
+                result = upload::send_chunk(&sender, chunk.to_vec()).await;
+
+                if result.is_err() {
+                    break;
+                }
+            }
+
+            result
+        }
+        UploadSource::Stream(format, request) => {
+            upload::send_codes(
+                request.into_body().into_data_stream(),
+                format,
+                chunk_size,
+                &sender,
+            )
+            .await
+        }
+        UploadSource::Multipart(request) => upload_multipart(request, chunk_size, &sender).await,
+    };
+
+    if let Err(err) = result {
+        // Fails the job as well, otherwise it would look like a smaller upload that succeeded
+        let _ = sender.send(Err(err.clone())).await;
+
+        return Err(ApiError::BadRequest(format!(
+            "Upload job {} failed: {}",
+            job.id, err
+        )));
     }
 
-    ctx.service.spawn_upload_job(set_id, coupons).await;
+    Ok(Json(job))
+}
+
+// Every file of the form is read, the format of each follows its content type and defaults to CSV
+async fn upload_multipart(
+    request: Request,
+    chunk_size: usize,
+    sender: &UploadSender,
+) -> Result<(), String> {
+    let mut multipart = Multipart::from_request(request, &())
+        .await
+        .map_err(|err| err.body_text())?;
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| err.body_text())?
+    {
+        if field.file_name().is_none() {
+            continue;
+        }
+
+        let format = field
+            .content_type()
+            .and_then(UploadFormat::from_content_type)
+            .unwrap_or(UploadFormat::Csv);
+
+        upload::send_codes(field, format, chunk_size, sender).await?;
+    }
 
     Ok(())
 }
 
-#[tracing::instrument(skip_all)]
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
+async fn generate_coupons(
+    State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
+    Path(set_id): Path<i64>,
+    Json(generate_dto): Json<GenerateCouponsDto>,
+) -> ApiResult<Json<UploadJob>> {
+    if generate_dto.count < 1 || generate_dto.count > ctx.generate_config.max_count {
+        return Err(ApiError::BadRequest(format!(
+            "Count must be between 1 and {}",
+            ctx.generate_config.max_count
+        )));
+    }
+
+    let job = ctx.service.spawn_generate_job(set_id, generate_dto).await?;
+    Ok(Json(job))
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
+async fn lookup_coupon(
+    State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
+    Path(code): Path<String>,
+) -> ApiResult<Json<CouponLookupResponseDto>> {
+    let result = ctx.service.lookup_coupon(&code).await?;
+    Ok(Json(result))
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
+async fn redeem_coupon(
+    State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
+    Path(code): Path<String>,
+    headers: HeaderMap,
+) -> ApiResult<Json<CouponLookupResponseDto>> {
+    let idempotency_key = idempotency_key(&headers)?;
+
+    let result = ctx
+        .service
+        .redeem_coupon(&code, idempotency_key.as_deref(), &principal)
+        .await?;
+    Ok(Json(result))
+}
+
+fn idempotency_key(headers: &HeaderMap) -> ApiResult<Option<String>> {
+    let Some(idempotency_key) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+
+    let idempotency_key = idempotency_key
+        .to_str()
+        .map_err(|_| ApiError::BadRequest("Invalid idempotency key".to_string()))?;
+
+    if idempotency_key.is_empty() || idempotency_key.len() > IDEMPOTENCY_KEY_MAX_LENGTH {
+        return Err(ApiError::BadRequest(format!(
+            "Idempotency key must have between 1 and {} characters",
+            IDEMPOTENCY_KEY_MAX_LENGTH
+        )));
+    }
+
+    Ok(Some(idempotency_key.to_string()))
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
+async fn get_upload_job(
+    State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
+    Path(job_id): Path<Uuid>,
+) -> ApiResult<Json<UploadJob>> {
+    let job = ctx.service.get_upload_job(job_id).await?;
+    Ok(Json(job))
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
+async fn get_upload_report(
+    State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
+    Path(job_id): Path<Uuid>,
+) -> ApiResult<Json<UploadReportResponseDto>> {
+    let report = ctx.service.get_upload_report(job_id).await?;
+    Ok(Json(report))
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
 async fn create_set(
     State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
     Json(create_dto): Json<CreateCouponSetDto>,
 ) -> ApiResult<Json<CouponSet>> {
+    if let (Some(starts_at), Some(ends_at)) = (create_dto.starts_at, create_dto.ends_at) {
+        if starts_at >= ends_at {
+            return Err(ApiError::BadRequest(
+                "Campaign must start before it ends".to_string(),
+            ));
+        }
+    }
+
+    if !create_dto.code_format.is_valid() {
+        return Err(ApiError::BadRequest(format!(
+            "Code formats need a length between 1 and {} and a non-empty alphabet",
+            MAX_CODE_LENGTH
+        )));
+    }
+
     let result = ctx.service.create_coupon_set(create_dto).await?;
     Ok(Json(result))
 }
 
-#[tracing::instrument(skip_all)]
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
+async fn update_set(
+    State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
+    Path(set_id): Path<i64>,
+    Json(update_dto): Json<UpdateCouponSetDto>,
+) -> ApiResult<Json<CouponSet>> {
+    let result = ctx
+        .service
+        .update_set_status(set_id, update_dto.status)
+        .await?;
+    Ok(Json(result))
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
+async fn delete_set(
+    State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
+    Path(set_id): Path<i64>,
+) -> ApiResult<()> {
+    ctx.service.delete_set(set_id).await?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(subject = %principal.subject, auth_source = ?principal.source)
+)]
 async fn set_status(
     State(ctx): State<Arc<CouponAppState>>,
+    principal: Principal,
 ) -> ApiResult<Json<Vec<CouponStatusResponseDto>>> {
     let result = ctx.service.set_status().await?;
     Ok(Json(result))
@@ -1,9 +1,58 @@
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::model::coupon::CouponCodeFormat;
+use crate::model::coupon::CouponSetStatus;
+use crate::model::coupon::CouponStatus;
+use crate::model::generator::CodeGenerator;
+use crate::model::upload::UploadJob;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateCouponSetDto {
     pub name: String,
+    #[serde(default)]
+    pub starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub ends_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub code_format: CouponCodeFormat,
+    #[serde(default)]
+    pub sticky: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateCouponSetDto {
+    pub status: CouponSetStatus,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadCouponsQuery {
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClaimCouponsDto {
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerateCouponsDto {
+    pub count: i64,
+    pub generator: CodeGenerator,
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CouponLookupResponseDto {
+    pub code: String,
+    pub set_id: i64,
+    pub status: CouponStatus,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub reserved_until: Option<chrono::DateTime<chrono::Utc>>,
+    pub issued_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub redeemed_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Serialize)]
@@ -11,6 +60,23 @@ pub struct CouponStatusResponseDto {
     pub id: i64,
     pub name: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    pub status: CouponSetStatus,
     pub total_database: i64,
     pub total_cache: i64,
+    pub total_issued: i64,
+    pub total_redeemed: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExistingCouponDto {
+    pub code: String,
+    pub set_id: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UploadReportResponseDto {
+    pub job: UploadJob,
+    pub invalid: Vec<String>,
+    pub duplicate: Vec<String>,
+    pub existing: Vec<ExistingCouponDto>,
 }
@@ -2,6 +2,7 @@ pub mod coupon;
 pub mod dto;
 pub mod files;
 pub mod metrics;
+pub mod upload;
 pub mod userlogin;
 pub mod worker;
 
@@ -19,7 +20,6 @@ use axum::http::Request;
 use axum::middleware;
 use axum::response::Response;
 use axum::Router;
-use redis::aio::MultiplexedConnection;
 use serde::Deserialize;
 use serde::Serialize;
 use sqlx::Pool;
@@ -29,10 +29,13 @@ use tower_http::trace::TraceLayer;
 
 use crate::auth::jwt::JWTService;
 use crate::auth::keycloak::KeycloakAuthMiddleware;
+use crate::auth::role::RoleConfig;
 use crate::auth::userlogin::UserAuthMiddleware;
-use crate::cache::lock::DistributedLock;
 use crate::metrics::Metrics;
-use crate::service::BatchInsertConfig;
+use crate::service::ClaimConfig;
+use crate::service::CouponServiceConfig;
+use crate::service::GenerateConfig;
+use crate::store::CouponStore;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct AxumApiConfig {
@@ -43,14 +46,16 @@ struct AxumApiConfig {
 #[derive(Clone)]
 pub struct AppState {
     pub db: Pool<Postgres>,
-    pub cache: MultiplexedConnection,
+    pub store: Arc<dyn CouponStore>,
     pub metrics: Metrics,
     pub timeout: Arc<Mutex<u64>>,
-    pub lock: DistributedLock,
-    pub batch_config: BatchInsertConfig,
+    pub coupon_config: CouponServiceConfig,
+    pub claim_config: ClaimConfig,
+    pub generate_config: GenerateConfig,
     pub jwt_service: JWTService,
     pub user_auth: UserAuthMiddleware,
     pub kc_auth: KeycloakAuthMiddleware,
+    pub role_config: RoleConfig,
 }
 
 #[tracing::instrument(skip(ctx))]
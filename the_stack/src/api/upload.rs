@@ -0,0 +1,132 @@
+use std::fmt::Display;
+
+use axum::body::Bytes;
+use futures::Stream;
+use futures::StreamExt;
+
+use crate::service::coupon::UploadSender;
+
+// A single code is way shorter, anything longer means the file is not what we expect
+const MAX_LINE_LENGTH: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadFormat {
+    Csv,
+    Ndjson,
+}
+
+impl UploadFormat {
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        if content_type.starts_with("text/csv") {
+            Some(Self::Csv)
+        } else if content_type.starts_with("application/x-ndjson") {
+            Some(Self::Ndjson)
+        } else {
+            None
+        }
+    }
+
+    // CSV takes the first column and skips an optional `code` header, NDJSON takes a JSON string
+    // per line. Lines that can't be read are kept as is so that they show up in the report.
+    fn parse_line(&self, line: &[u8], first: bool) -> Option<String> {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+
+        if line.is_empty() {
+            return None;
+        }
+
+        match self {
+            Self::Csv => {
+                let code = line
+                    .split(',')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .trim_matches('"');
+
+                if first && code.eq_ignore_ascii_case("code") {
+                    return None;
+                }
+
+                Some(code.to_string())
+            }
+            Self::Ndjson => {
+                Some(serde_json::from_str::<String>(line).unwrap_or_else(|_| line.to_string()))
+            }
+        }
+    }
+}
+
+// Reads the stream line by line and hands the codes to the upload job in chunks, so only one
+// chunk and a partial line are held in memory at a time
+pub async fn send_codes<S, E>(
+    stream: S,
+    format: UploadFormat,
+    chunk_size: usize,
+    sender: &UploadSender,
+) -> Result<(), String>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: Display,
+{
+    let mut stream = std::pin::pin!(stream);
+
+    let mut buffer: Vec<u8> = vec![];
+    let mut chunk = Vec::with_capacity(chunk_size);
+    let mut first = true;
+
+    loop {
+        let next = stream.next().await;
+
+        let end = match next {
+            Some(bytes) => {
+                buffer.extend_from_slice(&bytes.map_err(|err| err.to_string())?);
+                false
+            }
+            None => {
+                // The last line doesn't need a trailing newline
+                buffer.push(b'\n');
+                true
+            }
+        };
+
+        let mut start = 0;
+
+        while let Some(pos) = buffer[start..].iter().position(|byte| *byte == b'\n') {
+            if let Some(code) = format.parse_line(&buffer[start..start + pos], first) {
+                chunk.push(code);
+            }
+
+            first = false;
+            start += pos + 1;
+
+            if chunk.len() >= chunk_size {
+                send_chunk(sender, std::mem::take(&mut chunk)).await?;
+            }
+        }
+
+        buffer.drain(..start);
+
+        if buffer.len() > MAX_LINE_LENGTH {
+            return Err(format!("Line is longer than {} bytes", MAX_LINE_LENGTH));
+        }
+
+        if end {
+            break;
+        }
+    }
+
+    if !chunk.is_empty() {
+        send_chunk(sender, chunk).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn send_chunk(sender: &UploadSender, chunk: Vec<String>) -> Result<(), String> {
+    sender
+        .send(Ok(chunk))
+        .await
+        .map_err(|_| "Upload job stopped receiving coupons".to_string())
+}
@@ -0,0 +1,173 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use jsonwebtoken::jwk::Jwk;
+use jsonwebtoken::jwk::JwkSet;
+use reqwest::Url;
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use crate::metrics::Metrics;
+
+#[derive(Deserialize, Clone)]
+pub struct JwksConfig {
+    // Age after which the keys are refetched, the background refresh runs twice per period
+    #[serde(rename(deserialize = "auth_keycloak_jwks_ttl_seconds"))]
+    pub ttl_seconds: u64,
+    // Minimum time between two fetches, so that tokens with made up key ids can't hammer Keycloak
+    #[serde(rename(deserialize = "auth_keycloak_jwks_refetch_seconds"))]
+    pub refetch_seconds: u64,
+}
+
+impl JwksConfig {
+    pub fn new() -> anyhow::Result<Self> {
+        envy::from_env().context("Failed to get env vars")
+    }
+}
+
+#[derive(Default)]
+struct JwksState {
+    jwks: Option<JwkSet>,
+    fetched_at: Option<Instant>,
+    // Bumped on every successful fetch, callers that waited for a fetch compare it to skip theirs
+    generation: u64,
+}
+
+// Keys are kept after a failed refresh, a Keycloak outage shouldn't reject every token signed with
+// a key that was valid a minute ago
+#[derive(Clone)]
+pub struct JwksCache {
+    endpoint: Url,
+    client: reqwest::Client,
+    ttl: Duration,
+    refetch: Duration,
+    metrics: Metrics,
+    state: Arc<RwLock<JwksState>>,
+    // Write locked while a fetch is in flight, callers that need keys wait on a read lock
+    fetching: Arc<RwLock<()>>,
+    last_attempt: Arc<Mutex<Option<Instant>>>,
+}
+
+impl JwksCache {
+    pub fn new(endpoint: Url, config: JwksConfig, metrics: Metrics) -> Self {
+        Self {
+            endpoint,
+            client: reqwest::Client::new(),
+            ttl: Duration::from_secs(config.ttl_seconds),
+            refetch: Duration::from_secs(config.refetch_seconds),
+            metrics,
+            state: Arc::new(RwLock::new(JwksState::default())),
+            fetching: Arc::new(RwLock::new(())),
+            last_attempt: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Refreshes the keys before they go stale, so that requests don't have to wait for a fetch
+    pub fn spawn_refresh(&self) {
+        let cache = self.clone();
+        let period = (self.ttl / 2).max(Duration::from_secs(1));
+
+        tokio::task::spawn(async move {
+            loop {
+                let generation = cache.state.read().await.generation;
+                cache.refresh(generation).await;
+
+                tokio::time::sleep(period).await;
+            }
+        });
+    }
+
+    // Errors when no keys could be fetched at all, None means that the key id is unknown
+    pub async fn find(&self, kid: &str) -> anyhow::Result<Option<Jwk>> {
+        let (found, generation, fresh) = self.lookup(kid).await;
+
+        match found {
+            Some(jwk) if fresh => return Ok(Some(jwk)),
+            Some(_) => {}
+            None if generation > 0 => self.metrics.jwks_unknown_kid.inc(),
+            None => {}
+        }
+
+        self.refresh(generation).await;
+
+        let state = self.state.read().await;
+
+        let Some(jwks) = state.jwks.as_ref() else {
+            return Err(anyhow!("jwks could not be fetched"));
+        };
+
+        Ok(jwks.find(kid).cloned())
+    }
+
+    async fn lookup(&self, kid: &str) -> (Option<Jwk>, u64, bool) {
+        let state = self.state.read().await;
+
+        let found = state.jwks.as_ref().and_then(|jwks| jwks.find(kid)).cloned();
+        let fresh = state
+            .fetched_at
+            .is_some_and(|fetched_at| fetched_at.elapsed() < self.ttl);
+
+        (found, state.generation, fresh)
+    }
+
+    // Only one caller fetches at a time, the ones arriving during the fetch reuse its result
+    async fn refresh(&self, seen_generation: u64) {
+        let Ok(_fetching) = self.fetching.try_write() else {
+            let _ = self.fetching.read().await;
+            return;
+        };
+
+        if self.state.read().await.generation != seen_generation {
+            return;
+        }
+
+        {
+            let mut last_attempt = self
+                .last_attempt
+                .lock()
+                .expect("Could not acquire lock for jwks");
+
+            if last_attempt.is_some_and(|at| at.elapsed() < self.refetch) {
+                return;
+            }
+
+            *last_attempt = Some(Instant::now());
+        }
+
+        tracing::info!(generation = seen_generation, "fetching the jwks");
+        self.metrics.jwks_fetches.inc();
+
+        match self.fetch().await {
+            Ok(jwks) => {
+                let mut state = self.state.write().await;
+
+                state.jwks = Some(jwks);
+                state.fetched_at = Some(Instant::now());
+                state.generation += 1;
+            }
+            Err(err) => {
+                self.metrics.jwks_fetch_failures.inc();
+
+                let error = format!("{:#}", err);
+                tracing::error!(error, "failed to fetch the jwks");
+            }
+        }
+    }
+
+    async fn fetch(&self) -> anyhow::Result<JwkSet> {
+        let jwks = self
+            .client
+            .get(self.endpoint.to_owned())
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<JwkSet>()
+            .await?;
+
+        Ok(jwks)
+    }
+}
@@ -1,57 +1,158 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::str::FromStr;
 
 use anyhow::anyhow;
+use anyhow::Context;
 use axum::extract::Request;
 use axum::extract::State;
 use axum::http::HeaderMap;
 use axum::middleware::Next;
 use axum::response::Response;
 use chrono::DateTime;
-use chrono::Utc;
-use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::errors::ErrorKind;
 use jsonwebtoken::Algorithm;
 use jsonwebtoken::DecodingKey;
 use jsonwebtoken::Validation;
 use reqwest::Url;
 use serde::Deserialize;
 
-use super::jwt::Claims;
+use super::jwks::JwksCache;
+use super::jwks::JwksConfig;
+use super::AuthSource;
+use super::Principal;
 use crate::error::api::ApiError;
 use crate::error::api::ApiResult;
+use crate::metrics::Metrics;
 
 #[derive(Deserialize)]
 struct AuthConfigFromEnv {
     #[serde(rename(deserialize = "auth_keycloak_jwks_endpoint"))]
     pub jwks_endpoint: String,
+    // Client whose roles count next to the realm roles
+    #[serde(rename(deserialize = "auth_keycloak_client_id"))]
+    pub client_id: Option<String>,
+    // Realm urls, comma separated, tokens from any other realm are rejected
+    #[serde(rename(deserialize = "auth_keycloak_issuers"))]
+    pub issuers: Vec<String>,
+    // Client ids, comma separated, matched against `aud` and `azp`
+    #[serde(rename(deserialize = "auth_keycloak_audience"))]
+    pub audience: Vec<String>,
+    #[serde(rename(deserialize = "auth_keycloak_algorithms"))]
+    pub algorithms: Vec<String>,
+    // Allowed clock skew between Keycloak and the service
+    #[serde(rename(deserialize = "auth_keycloak_leeway_seconds"))]
+    pub leeway_seconds: u64,
 }
 
 #[derive(Clone)]
 struct AuthConfig {
-    jwks_endpoint: Url,
+    client_id: Option<String>,
+    issuers: Vec<String>,
+    audience: HashSet<String>,
+    algorithms: Vec<Algorithm>,
+    leeway_seconds: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct RoleClaims {
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+// Claims of a Keycloak access token, only the ones the service looks at
+#[derive(Debug, Deserialize)]
+pub struct KeycloakClaims {
+    pub sub: String,
+    pub exp: i64,
+    pub iss: String,
+    pub aud: Option<Audience>,
+    // Client the token was issued to
+    pub azp: Option<String>,
+    pub email: Option<String>,
+    // Realm roles
+    pub realm_access: Option<RoleClaims>,
+    // Client roles, by client id
+    #[serde(default)]
+    pub resource_access: HashMap<String, RoleClaims>,
+}
+
+impl KeycloakClaims {
+    // Realm roles and the roles of the given client, roles of other clients are ignored
+    pub fn roles(&self, client_id: Option<&str>) -> Vec<String> {
+        let realm_roles = self
+            .realm_access
+            .iter()
+            .flat_map(|access| access.roles.iter());
+        let client_roles = client_id
+            .and_then(|client_id| self.resource_access.get(client_id))
+            .into_iter()
+            .flat_map(|access| access.roles.iter());
+
+        realm_roles.chain(client_roles).cloned().collect()
+    }
+
+    // Keycloak only puts clients with roles in `aud`, the client that asked for the token is in
+    // `azp`
+    pub fn is_for(&self, audience: &HashSet<String>) -> bool {
+        let aud = match &self.aud {
+            Some(Audience::Single(aud)) => std::slice::from_ref(aud),
+            Some(Audience::Multiple(aud)) => aud.as_slice(),
+            None => &[],
+        };
+
+        aud.iter()
+            .chain(self.azp.iter())
+            .any(|client| audience.contains(client))
+    }
 }
 
 #[derive(Clone)]
 pub struct KeycloakAuthMiddleware {
     config: AuthConfig,
-    client: reqwest::Client,
+    jwks: JwksCache,
 }
 
 impl KeycloakAuthMiddleware {
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new(metrics: Metrics) -> anyhow::Result<Self> {
         let config = envy::from_env::<AuthConfigFromEnv>()?;
 
         let jwks_endpoint = Url::from_str(&config.jwks_endpoint)?;
 
+        let algorithms = config
+            .algorithms
+            .iter()
+            .map(|algorithm| {
+                Algorithm::from_str(algorithm).with_context(|| format!("Algorithm: {}", algorithm))
+            })
+            .collect::<anyhow::Result<Vec<Algorithm>>>()?;
+
+        let jwks = JwksCache::new(jwks_endpoint, JwksConfig::new()?, metrics);
+        jwks.spawn_refresh();
+
         Ok(Self {
-            config: AuthConfig { jwks_endpoint },
-            client: reqwest::Client::new(),
+            config: AuthConfig {
+                client_id: config.client_id,
+                issuers: config.issuers,
+                audience: config.audience.into_iter().collect(),
+                algorithms,
+                leeway_seconds: config.leeway_seconds,
+            },
+            jwks,
         })
     }
 
     pub async fn authenticate(
         State(state): State<KeycloakAuthMiddleware>,
         headers: HeaderMap,
-        req: Request,
+        mut req: Request,
         next: Next,
     ) -> ApiResult<Response> {
         let Some(bearer_auth) = headers.get(axum::http::header::AUTHORIZATION) else {
@@ -62,49 +163,88 @@ impl KeycloakAuthMiddleware {
             .strip_prefix("Bearer ")
             .ok_or(ApiError::default_unauthorized())?;
 
-        // TODO cache this
-        let jwks = state
-            .client
-            .get(state.config.jwks_endpoint.to_owned())
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<JwkSet>()
-            .await?;
-
-        let headers = jsonwebtoken::decode_header(token)?;
-
-        let key_id = headers
-            .kid
-            .ok_or(ApiError::Internal(anyhow!("invalid kid header")))?;
-
-        let public_key = jwks.find(&key_id).ok_or(ApiError::Internal(anyhow!(
-            "no matching public key in jwks"
-        )))?;
-
-        let decoding_key = DecodingKey::from_jwk(public_key)?;
-
-        // TODO use different type for Claims
-        let token_data = jsonwebtoken::decode::<Claims>(
-            token,
-            &decoding_key,
-            &Validation::new(Algorithm::RS256),
-        )
-        .map_err(|e| ApiError::Unauthorized {
-            message: "invalid jwt".to_string(),
-            error: Some(e.into()),
+        let headers =
+            jsonwebtoken::decode_header(token).map_err(|e| rejected("malformed token", e))?;
+
+        // Checked here, jsonwebtoken wants every allowed algorithm to fit the key
+        if !state.config.algorithms.contains(&headers.alg) {
+            return Err(ApiError::Unauthorized {
+                message: format!("signing algorithm {:?} is not allowed", headers.alg),
+                error: None,
+            });
+        }
+
+        let key_id = headers.kid.ok_or(ApiError::Unauthorized {
+            message: "missing key id".to_string(),
+            error: None,
         })?;
 
-        let exp = DateTime::from_timestamp(token_data.claims.exp.try_into()?, 0).ok_or(
-            ApiError::Internal(anyhow!("exp claim conversion to timestamp error")),
-        )?;
-        if Utc::now() > exp {
+        let public_key = state
+            .jwks
+            .find(&key_id)
+            .await
+            .map_err(ApiError::Internal)?
+            .ok_or(ApiError::Unauthorized {
+                message: "unknown signing key".to_string(),
+                error: None,
+            })?;
+
+        let decoding_key = DecodingKey::from_jwk(&public_key)?;
+
+        let mut validation = Validation::new(headers.alg);
+        validation.set_required_spec_claims(&["sub", "exp", "iss"]);
+        validation.set_issuer(&state.config.issuers);
+        validation.leeway = state.config.leeway_seconds;
+        // `azp` counts as well, so the audience is checked below
+        validation.validate_aud = false;
+
+        let token_data = jsonwebtoken::decode::<KeycloakClaims>(token, &decoding_key, &validation)
+            .map_err(|e| {
+                let reason = match e.kind() {
+                    ErrorKind::ExpiredSignature => "token expired",
+                    ErrorKind::ImmatureSignature => "token not valid yet",
+                    ErrorKind::InvalidIssuer => "token issued by another realm",
+                    ErrorKind::InvalidSignature => "invalid signature",
+                    ErrorKind::MissingRequiredClaim(_) => "missing required claim",
+                    _ => "invalid jwt",
+                };
+
+                rejected(reason, e)
+            })?;
+
+        let claims = token_data.claims;
+
+        if !claims.is_for(&state.config.audience) {
             return Err(ApiError::Unauthorized {
-                message: "token expired".to_string(),
+                message: format!(
+                    "token issued to client {} from another audience",
+                    claims.azp.as_deref().unwrap_or("unknown")
+                ),
                 error: None,
             });
         }
 
+        let expires_at = DateTime::from_timestamp(claims.exp, 0).ok_or(ApiError::Internal(
+            anyhow!("exp claim conversion to timestamp error"),
+        ))?;
+
+        let roles = claims.roles(state.config.client_id.as_deref());
+
+        req.extensions_mut().insert(Principal {
+            subject: claims.sub,
+            email: claims.email,
+            roles,
+            source: AuthSource::Keycloak,
+            expires_at,
+        });
+
         Ok(next.run(req).await)
     }
 }
+
+fn rejected(reason: &str, error: jsonwebtoken::errors::Error) -> ApiError {
+    ApiError::Unauthorized {
+        message: reason.to_string(),
+        error: Some(error.into()),
+    }
+}
@@ -1,3 +1,54 @@
+pub mod jwks;
 pub mod jwt;
 pub mod keycloak;
+pub mod role;
 pub mod userlogin;
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use chrono::DateTime;
+use chrono::Utc;
+
+use crate::error::api::ApiError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthSource {
+    Keycloak,
+    UserLogin,
+}
+
+// Caller a request was authenticated as, inserted into the request extensions by the auth
+// middlewares
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub subject: String,
+    pub email: Option<String>,
+    pub roles: Vec<String>,
+    pub source: AuthSource,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Principal {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+// Handlers behind one of the auth middlewares take a `Principal` argument, anywhere else the
+// request is rejected as unauthorized
+#[async_trait]
+impl<S> FromRequestParts<S> for Principal
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Principal>()
+            .cloned()
+            .ok_or(ApiError::default_unauthorized())
+    }
+}
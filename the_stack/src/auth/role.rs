@@ -0,0 +1,59 @@
+use anyhow::Context;
+use axum::extract::Request;
+use axum::extract::State;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+
+use super::Principal;
+use crate::error::api::ApiError;
+use crate::error::api::ApiResult;
+
+#[derive(Deserialize, Clone)]
+pub struct RoleConfig {
+    // Manages coupon sets and uploads
+    #[serde(rename(deserialize = "auth_admin_role"))]
+    pub admin: String,
+    // Hands out and redeems coupons, e.g. a storefront service account
+    #[serde(rename(deserialize = "auth_consumer_role"))]
+    pub consumer: String,
+}
+
+impl RoleConfig {
+    pub fn new() -> anyhow::Result<Self> {
+        envy::from_env().context("Failed to get env vars")
+    }
+}
+
+// Route layer that only lets callers with the role through, has to run after an auth middleware
+#[derive(Clone)]
+pub struct RoleGuard {
+    role: String,
+}
+
+impl RoleGuard {
+    pub fn new(role: &str) -> Self {
+        Self {
+            role: role.to_string(),
+        }
+    }
+
+    pub async fn require(
+        State(guard): State<RoleGuard>,
+        principal: Principal,
+        req: Request,
+        next: Next,
+    ) -> ApiResult<Response> {
+        if !principal.has_role(&guard.role) {
+            tracing::warn!(
+                subject = principal.subject,
+                role = guard.role,
+                "caller is missing the required role"
+            );
+
+            return Err(ApiError::Forbidden(format!("Missing role {}", guard.role)));
+        }
+
+        Ok(next.run(req).await)
+    }
+}
@@ -8,6 +8,8 @@ use chrono::Utc;
 use tower_cookies::Cookies;
 
 use super::jwt::JWTService;
+use super::AuthSource;
+use super::Principal;
 use crate::error::api::ApiError;
 use crate::error::api::ApiResult;
 use crate::service::userlogin::UserLoginService;
@@ -29,7 +31,7 @@ impl UserAuthMiddleware {
     pub async fn auth_middleware(
         State(state): State<UserAuthMiddleware>,
         cookies: Cookies,
-        req: Request,
+        mut req: Request,
         next: Next,
     ) -> ApiResult<Response> {
         let Some(auth_cookie) = cookies.get(&state.user_login.auth_cookie()) else {
@@ -57,6 +59,14 @@ impl UserAuthMiddleware {
             });
         }
 
+        req.extensions_mut().insert(Principal {
+            email: Some(token_data.claims.sub.clone()),
+            subject: token_data.claims.sub,
+            roles: vec![],
+            source: AuthSource::UserLogin,
+            expires_at: exp,
+        });
+
         Ok(next.run(req).await)
     }
 }
@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use prometheus::Gauge;
+
+use crate::error::cache::CacheError;
+use crate::error::cache::CacheResult;
+
+const STATE_CLOSED: f64 = 0.0;
+const STATE_HALF_OPEN: f64 = 1.0;
+const STATE_OPEN: f64 = 2.0;
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    // Set while the breaker is open, moved forward every time a probe is let through
+    opened_at: Option<Instant>,
+}
+
+// Stops calling the cache after `failure_threshold` failures in a row. While open, one caller per
+// `open_for` period is let through to probe redis, the first success closes the breaker again.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    state: Arc<Mutex<BreakerState>>,
+    failure_threshold: u32,
+    open_for: Duration,
+    gauge: Gauge,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_for: Duration, gauge: Gauge) -> Self {
+        gauge.set(STATE_CLOSED);
+
+        Self {
+            state: Arc::new(Mutex::new(BreakerState::default())),
+            failure_threshold,
+            open_for,
+            gauge,
+        }
+    }
+
+    // Whether the cache should be called
+    pub fn allow(&self) -> bool {
+        let mut state = self
+            .state
+            .lock()
+            .expect("Could not acquire lock for breaker");
+
+        match state.opened_at {
+            None => true,
+            Some(opened_at) if opened_at.elapsed() >= self.open_for => {
+                state.opened_at = Some(Instant::now());
+                self.gauge.set(STATE_HALF_OPEN);
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        let state = self
+            .state
+            .lock()
+            .expect("Could not acquire lock for breaker");
+
+        state.opened_at.is_some()
+    }
+
+    // Only internal errors count as failures, a missing key means redis answered
+    pub fn record<T>(&self, result: &CacheResult<T>) {
+        match result {
+            Err(CacheError::Internal(_)) => self.failure(),
+            _ => self.success(),
+        }
+    }
+
+    fn success(&self) {
+        let mut state = self
+            .state
+            .lock()
+            .expect("Could not acquire lock for breaker");
+
+        if state.opened_at.is_some() {
+            tracing::info!("cache is back, closing the circuit breaker");
+        }
+
+        *state = BreakerState::default();
+        self.gauge.set(STATE_CLOSED);
+    }
+
+    fn failure(&self) {
+        let mut state = self
+            .state
+            .lock()
+            .expect("Could not acquire lock for breaker");
+
+        state.failures = state.failures.saturating_add(1);
+
+        if state.opened_at.is_none() && state.failures < self.failure_threshold {
+            return;
+        }
+
+        if state.opened_at.is_none() {
+            tracing::warn!(
+                failures = state.failures,
+                "cache is unhealthy, opening the circuit breaker"
+            );
+        }
+
+        state.opened_at = Some(Instant::now());
+        self.gauge.set(STATE_OPEN);
+    }
+}
@@ -1,25 +1,158 @@
-use std::num::NonZeroUsize;
-
-use redis::aio::MultiplexedConnection;
+use redis::aio::ConnectionManager;
 use redis::AsyncCommands;
-use redis::Direction;
+use redis::Script;
 
 use crate::error::cache::CacheResult;
 use crate::model::coupon::Coupon;
+use crate::model::coupon::CouponIssue;
 use crate::model::coupon::CouponSet;
 use crate::model::coupon::CouponSetCacheStatus;
+use crate::model::coupon::IdempotencyClaim;
+
+// How long a refill signal is held before another caller can be told to refill the same set
+const REFILL_MARKER_TTL_MILLIS: u64 = 1000;
+
+// Pops a coupon from the set and records it in the destination list (used or reserved) in a single
+// step, along with who it was issued to and when unless ARGV[2] is empty (see `CouponIssue`). When
+// the set runs out of coupons, only the first caller is told to refill it, until the refill marker
+// expires.
+static POP_COUPON_SCRIPT: &str = r"
+local coupon = redis.call('RPOP', KEYS[1])
+if coupon then
+    redis.call('LPUSH', KEYS[2], coupon)
+    if ARGV[2] ~= '' then
+        local now = redis.call('TIME')
+        local issued = now[1] .. string.format('%06d', tonumber(now[2])) .. ':' .. ARGV[2]
+        redis.call('HSET', KEYS[4], coupon, issued)
+    end
+end
+local refill = 0
+if redis.call('LLEN', KEYS[1]) == 0 then
+    if redis.call('SET', KEYS[3], 1, 'NX', 'PX', ARGV[1]) then
+        refill = 1
+    end
+end
+return {coupon, refill}
+";
+
+// Same as the pop script, but for many coupons at once. LPUSH is done in chunks so that `unpack`
+// doesn't go over the Lua stack limit.
+static POP_COUPONS_SCRIPT: &str = r"
+local coupons = redis.call('RPOP', KEYS[1], ARGV[1])
+if not coupons then
+    return {}
+end
+for i = 1, #coupons, 1000 do
+    redis.call('LPUSH', KEYS[2], unpack(coupons, i, math.min(i + 999, #coupons)))
+end
+if ARGV[2] ~= '' then
+    local now = redis.call('TIME')
+    local issued = now[1] .. string.format('%06d', tonumber(now[2])) .. ':' .. ARGV[2]
+    for _, coupon in ipairs(coupons) do
+        redis.call('HSET', KEYS[3], coupon, issued)
+    end
+end
+return coupons
+";
+
+// Moves reserved coupons back to the set list. Only coupons that were still in the reserved list are
+// pushed, so that giving the same coupon back twice doesn't hand it out twice.
+static RETURN_COUPONS_SCRIPT: &str = r"
+local returned = 0
+for _, coupon in ipairs(ARGV) do
+    if redis.call('LREM', KEYS[1], 1, coupon) > 0 then
+        redis.call('LPUSH', KEYS[2], coupon)
+        returned = returned + 1
+    end
+end
+return returned
+";
+
+// Placeholder stored under an idempotency key while its coupon is being popped
+const IDEMPOTENCY_PENDING: &str = "pending";
+
+pub struct PoppedCoupon {
+    pub coupon: Option<String>,
+    pub refill: bool,
+}
+
+pub struct CachedCoupons {
+    // Still in the set list, waiting to be popped
+    pub available: Vec<String>,
+    // In the used, in-flight or reserved lists
+    pub taken: Vec<String>,
+}
 
 #[derive(Clone)]
 pub struct CouponCache {
-    conn: MultiplexedConnection,
+    conn: ConnectionManager,
+    pop_script: Script,
+    pop_many_script: Script,
+    return_script: Script,
 }
 
 impl CouponCache {
-    pub fn new(conn: MultiplexedConnection) -> Self {
-        Self { conn }
+    pub fn new(conn: ConnectionManager) -> Self {
+        Self {
+            conn,
+            pop_script: Script::new(POP_COUPON_SCRIPT),
+            pop_many_script: Script::new(POP_COUPONS_SCRIPT),
+            return_script: Script::new(RETURN_COUPONS_SCRIPT),
+        }
+    }
+
+    // Loads the scripts into redis so that the first calls don't need to send the whole script
+    pub async fn load_scripts(&mut self) -> CacheResult<()> {
+        let _: String = self
+            .pop_script
+            .prepare_invoke()
+            .load_async(&mut self.conn)
+            .await?;
+        let _: String = self
+            .pop_many_script
+            .prepare_invoke()
+            .load_async(&mut self.conn)
+            .await?;
+        let _: String = self
+            .return_script
+            .prepare_invoke()
+            .load_async(&mut self.conn)
+            .await?;
+
+        Ok(())
     }
 
     #[tracing::instrument(skip(self, coupons))]
+    // Pushes coupons back into the set list, taking out any copy that is already in there so that
+    // a coupon is never listed twice
+    pub async fn requeue_coupons(&mut self, set_id: i64, coupons: &[Coupon]) -> CacheResult<()> {
+        if coupons.is_empty() {
+            return Ok(());
+        }
+
+        let codes = coupons
+            .iter()
+            .map(|c| c.code.clone())
+            .collect::<Vec<String>>();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for code in codes.iter() {
+            pipe.lrem(CouponSet::set_key(set_id), 0, code).ignore();
+        }
+
+        let _: () = pipe
+            .lpush(CouponSet::set_key(set_id), codes)
+            .ignore()
+            .sadd(CouponSet::registry_key(), set_id)
+            .ignore()
+            .query_async(&mut self.conn)
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn batch_insert(&mut self, set_id: i64, coupons: &[Coupon]) -> CacheResult<()> {
         if coupons.is_empty() {
             tracing::warn!("can't batch insert because there are no more coupons!");
@@ -28,62 +161,550 @@ impl CouponCache {
 
         tracing::info!("batch inserting {} coupons", coupons.len());
 
-        let _: () = self
-            .conn
+        let _: () = redis::pipe()
+            .atomic()
             .lpush(
                 CouponSet::set_key(set_id),
                 coupons
                     .iter()
-                    .map(|c| c.id.to_string())
+                    .map(|c| c.code.clone())
                     .collect::<Vec<String>>(),
             )
+            .ignore()
+            .sadd(CouponSet::registry_key(), set_id)
+            .ignore()
+            .query_async(&mut self.conn)
             .await?;
 
         Ok(())
     }
 
-    pub async fn pop_coupon(&mut self, set_id: i64) -> CacheResult<Option<String>> {
-        let result = self
+    pub async fn register_set(&mut self, set_id: i64) -> CacheResult<()> {
+        let _: () = self.conn.sadd(CouponSet::registry_key(), set_id).await?;
+
+        Ok(())
+    }
+
+    // Adds the sets that already have lists in the cache to the registry, so that sets cached
+    // before the registry existed are still seen by the status and cleanup. Uses SCAN so redis
+    // isn't blocked while the keyspace is walked.
+    pub async fn register_existing_sets(&mut self) -> CacheResult<()> {
+        let mut set_ids = vec![];
+
+        for pattern in [
+            CouponSet::set_key_prefix(),
+            CouponSet::used_key_prefix(),
+            CouponSet::in_flight_key_prefix(),
+        ] {
+            let mut keys = self.conn.scan_match::<_, String>(pattern).await?;
+
+            while let Some(key) = keys.next_item().await {
+                match CouponSet::extract_set_id(&key) {
+                    Some(set_id) => set_ids.push(set_id),
+                    None => tracing::warn!(key, "ignoring malformed coupon set key"),
+                }
+            }
+        }
+
+        if set_ids.is_empty() {
+            return Ok(());
+        }
+
+        let _: () = self.conn.sadd(CouponSet::registry_key(), set_ids).await?;
+
+        Ok(())
+    }
+
+    pub async fn active_set_ids(&mut self) -> CacheResult<Vec<i64>> {
+        let mut set_ids = vec![];
+        let mut members = self
             .conn
-            .lmove(
+            .sscan::<_, String>(CouponSet::registry_key())
+            .await?;
+
+        while let Some(member) = members.next_item().await {
+            match member.parse::<i64>() {
+                Ok(set_id) => set_ids.push(set_id),
+                Err(_) => tracing::warn!(member, "ignoring malformed coupon set id in registry"),
+            }
+        }
+
+        set_ids.sort_unstable();
+        set_ids.dedup();
+
+        Ok(set_ids)
+    }
+
+    pub async fn pop_coupon(
+        &mut self,
+        set_id: i64,
+        destination: &str,
+        issued_to: Option<&str>,
+    ) -> CacheResult<PoppedCoupon> {
+        let (coupon, refill): (Option<String>, bool) = self
+            .pop_script
+            .key(CouponSet::set_key(set_id))
+            .key(destination)
+            .key(CouponSet::refill_key(set_id))
+            .key(CouponSet::issued_key(set_id))
+            .arg(REFILL_MARKER_TTL_MILLIS)
+            .arg(issued_to.unwrap_or_default())
+            .invoke_async(&mut self.conn)
+            .await?;
+
+        Ok(PoppedCoupon { coupon, refill })
+    }
+
+    pub async fn pop_coupons(
+        &mut self,
+        set_id: i64,
+        count: i64,
+        destination: &str,
+        issued_to: Option<&str>,
+    ) -> CacheResult<Vec<String>> {
+        let result = self
+            .pop_many_script
+            .key(CouponSet::set_key(set_id))
+            .key(destination)
+            .key(CouponSet::issued_key(set_id))
+            .arg(count)
+            .arg(issued_to.unwrap_or_default())
+            .invoke_async(&mut self.conn)
+            .await?;
+
+        Ok(result)
+    }
+
+    // Records coupons that were taken directly from the database in the used or reserved lists,
+    // the same way the pop script does
+    pub async fn push_coupons(
+        &mut self,
+        key: &str,
+        coupons: &[Coupon],
+        issued_to: Option<&str>,
+    ) -> CacheResult<()> {
+        if coupons.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        pipe.lpush(
+            key,
+            coupons
+                .iter()
+                .map(|c| c.code.clone())
+                .collect::<Vec<String>>(),
+        )
+        .ignore();
+
+        if let Some(issued_to) = issued_to {
+            let issued = CouponIssue::new(issued_to).to_value();
+
+            for coupon in coupons.iter() {
+                pipe.hset(CouponSet::issued_key(coupon.set_id), &coupon.code, &issued)
+                    .ignore();
+            }
+        }
+
+        let _: () = pipe.query_async(&mut self.conn).await?;
+
+        Ok(())
+    }
+
+    pub async fn move_coupons(
+        &mut self,
+        source: &str,
+        destination: &str,
+        coupons: &[Coupon],
+        issued_to: Option<&str>,
+    ) -> CacheResult<()> {
+        if coupons.is_empty() {
+            return Ok(());
+        }
+
+        let codes = coupons
+            .iter()
+            .map(|c| c.code.clone())
+            .collect::<Vec<String>>();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for code in codes.iter() {
+            pipe.lrem(source, 1, code).ignore();
+        }
+
+        pipe.lpush(destination, codes).ignore();
+
+        if let Some(issued_to) = issued_to {
+            let issued = CouponIssue::new(issued_to).to_value();
+
+            for coupon in coupons.iter() {
+                pipe.hset(CouponSet::issued_key(coupon.set_id), &coupon.code, &issued)
+                    .ignore();
+            }
+        }
+
+        let _: () = pipe.query_async(&mut self.conn).await?;
+
+        Ok(())
+    }
+
+    // Returns how many of the coupons were still reserved
+    pub async fn return_coupons(&mut self, set_id: i64, coupons: &[Coupon]) -> CacheResult<i64> {
+        if coupons.is_empty() {
+            return Ok(0);
+        }
+
+        let mut invocation = self.return_script.prepare_invoke();
+        invocation
+            .key(CouponSet::reserved_key(set_id))
+            .key(CouponSet::set_key(set_id));
+
+        for coupon in coupons.iter() {
+            invocation.arg(&coupon.code);
+        }
+
+        let result = invocation.invoke_async(&mut self.conn).await?;
+
+        Ok(result)
+    }
+
+    // Coupons still waiting in the set list
+    pub async fn available_coupons(&mut self, set_id: i64) -> CacheResult<Vec<String>> {
+        let result = self.conn.lrange(CouponSet::set_key(set_id), 0, -1).await?;
+
+        Ok(result)
+    }
+
+    // Purged sets stay in the registry, their used coupons still have to be archived
+    pub async fn purge_sets(&mut self, set_ids: &[i64]) -> CacheResult<()> {
+        if set_ids.is_empty() {
+            return Ok(());
+        }
+
+        let keys = set_ids
+            .iter()
+            .flat_map(|set_id| [CouponSet::set_key(*set_id), CouponSet::refill_key(*set_id)])
+            .collect::<Vec<String>>();
+
+        let _: () = self.conn.del(keys).await?;
+
+        Ok(())
+    }
+
+    // Coupons in the used and in-flight lists, the ones that were handed out but not archived yet
+    pub async fn handed_out_coupons(&mut self, set_id: i64) -> CacheResult<Vec<String>> {
+        let (mut used, in_flight): (Vec<String>, Vec<String>) = redis::pipe()
+            .atomic()
+            .lrange(CouponSet::used_key(set_id), 0, -1)
+            .lrange(CouponSet::in_flight_key(set_id), 0, -1)
+            .query_async(&mut self.conn)
+            .await?;
+
+        used.extend(in_flight);
+
+        Ok(used)
+    }
+
+    // Removes everything the cache holds for a set
+    pub async fn drain_set(&mut self, set_id: i64) -> CacheResult<()> {
+        let _: () = redis::pipe()
+            .atomic()
+            .del(&[
                 CouponSet::set_key(set_id),
                 CouponSet::used_key(set_id),
-                Direction::Right,
-                Direction::Left,
+                CouponSet::in_flight_key(set_id),
+                CouponSet::reserved_key(set_id),
+                CouponSet::refill_key(set_id),
+                CouponSet::assigned_key(set_id),
+                CouponSet::issued_key(set_id),
+            ])
+            .ignore()
+            .srem(CouponSet::registry_key(), set_id)
+            .ignore()
+            .query_async(&mut self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    // Removes coupons from the set list, returning the ones that were actually in there
+    pub async fn remove_coupons(
+        &mut self,
+        set_id: i64,
+        coupons: &[Coupon],
+    ) -> CacheResult<Vec<Coupon>> {
+        if coupons.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut pipe = redis::Pipeline::with_capacity(coupons.len());
+
+        for coupon in coupons.iter() {
+            pipe.lrem(CouponSet::set_key(set_id), 1, &coupon.code);
+        }
+
+        let removed: Vec<i64> = pipe.query_async(&mut self.conn).await?;
+
+        // Assume that coupons.len() == removed.len()
+        let result = coupons
+            .iter()
+            .zip(removed)
+            .filter(|(_, removed)| *removed > 0)
+            .map(|(coupon, _)| coupon.clone())
+            .collect();
+
+        Ok(result)
+    }
+
+    // Set when the coupon was handed out and the cleanup worker didn't archive it yet
+    pub async fn issued_coupon(
+        &mut self,
+        set_id: i64,
+        code: &str,
+    ) -> CacheResult<Option<CouponIssue>> {
+        let result: Option<String> = self.conn.hget(CouponSet::issued_key(set_id), code).await?;
+
+        Ok(result.as_deref().map(CouponIssue::from_value))
+    }
+
+    pub async fn clear_refill(&mut self, set_id: i64) -> CacheResult<()> {
+        let _: () = self.conn.del(CouponSet::refill_key(set_id)).await?;
+
+        Ok(())
+    }
+
+    pub async fn assigned_coupon(
+        &mut self,
+        set_id: i64,
+        subject: &str,
+    ) -> CacheResult<Option<String>> {
+        let result = self
+            .conn
+            .hget(CouponSet::assigned_key(set_id), subject)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn assign_coupon(
+        &mut self,
+        set_id: i64,
+        subject: &str,
+        coupon: &str,
+    ) -> CacheResult<()> {
+        let _: () = self
+            .conn
+            .hset(CouponSet::assigned_key(set_id), subject, coupon)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn claim_idempotency_key(
+        &mut self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+        pending_seconds: u64,
+    ) -> CacheResult<IdempotencyClaim> {
+        // Returns the previous value when the key already exists, and nil when it was just set
+        let previous: Option<String> = redis::cmd("SET")
+            .arg(CouponSet::idempotency_key(set_id, subject, key))
+            .arg(IDEMPOTENCY_PENDING)
+            .arg("NX")
+            .arg("GET")
+            .arg("EX")
+            .arg(pending_seconds)
+            .query_async(&mut self.conn)
+            .await?;
+
+        let result = match previous {
+            None => IdempotencyClaim::New,
+            Some(value) if value == IDEMPOTENCY_PENDING => IdempotencyClaim::Pending,
+            Some(value) => IdempotencyClaim::Replay(value),
+        };
+
+        Ok(result)
+    }
+
+    pub async fn store_idempotency_key(
+        &mut self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+        coupon: &str,
+        window_seconds: u64,
+    ) -> CacheResult<()> {
+        let _: () = self
+            .conn
+            .set_ex(
+                CouponSet::idempotency_key(set_id, subject, key),
+                coupon,
+                window_seconds,
             )
             .await?;
 
+        Ok(())
+    }
+
+    pub async fn release_idempotency_key(
+        &mut self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+    ) -> CacheResult<()> {
+        let _: () = self
+            .conn
+            .del(CouponSet::idempotency_key(set_id, subject, key))
+            .await?;
+
+        Ok(())
+    }
+
+    // Sets that have used coupons waiting to be archived, or a batch that is still in flight
+    pub async fn used_set_ids(&mut self) -> CacheResult<Vec<i64>> {
+        let set_ids = self.active_set_ids().await?;
+
+        let mut pipe = redis::Pipeline::with_capacity(set_ids.len() * 2);
+
+        for set_id in set_ids.iter() {
+            pipe.llen(CouponSet::used_key(*set_id));
+            pipe.llen(CouponSet::in_flight_key(*set_id));
+        }
+
+        let lengths: Vec<i64> = pipe.query_async(&mut self.conn).await?;
+
+        let result = set_ids
+            .into_iter()
+            .zip(lengths.chunks(2))
+            .filter(|(_, lengths)| lengths.iter().any(|len| *len > 0))
+            .map(|(set_id, _)| set_id)
+            .collect();
+
         Ok(result)
     }
 
-    pub async fn pop_coupon_list(&mut self, key: &str) -> CacheResult<Vec<String>> {
-        let result = self.conn.lpop(key, NonZeroUsize::new(10000)).await?;
+    // Moves a batch of used coupons to the in-flight list, where they stay until `ack_in_flight`
+    pub async fn claim_used_coupons(
+        &mut self,
+        set_id: i64,
+        count: i64,
+    ) -> CacheResult<Vec<String>> {
+        // Used coupons keep their issued entries until they are acknowledged
+        let result = self
+            .pop_many_script
+            .key(CouponSet::used_key(set_id))
+            .key(CouponSet::in_flight_key(set_id))
+            .key(CouponSet::issued_key(set_id))
+            .arg(count)
+            .arg("")
+            .invoke_async(&mut self.conn)
+            .await?;
 
         Ok(result)
     }
 
-    pub async fn set_status(&mut self) -> CacheResult<Vec<CouponSetCacheStatus>> {
-        let keys = self
+    pub async fn in_flight_coupons(&mut self, set_id: i64) -> CacheResult<Vec<String>> {
+        let result = self
             .conn
-            .keys::<_, Vec<String>>(CouponSet::set_key_prefix())
+            .lrange(CouponSet::in_flight_key(set_id), 0, -1)
             .await?;
 
-        tracing::info!("found {} keys", keys.len());
+        Ok(result)
+    }
 
-        let mut pipe = redis::Pipeline::with_capacity(keys.len());
+    // Who each coupon was issued to and when, None for coupons that were not recorded
+    pub async fn issued_coupons(
+        &mut self,
+        set_id: i64,
+        codes: &[String],
+    ) -> CacheResult<Vec<Option<CouponIssue>>> {
+        if codes.is_empty() {
+            return Ok(vec![]);
+        }
 
-        for key in keys.iter() {
-            pipe.llen(key);
+        // HMGET even for a single code, so that the reply is always a list
+        let result: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(CouponSet::issued_key(set_id))
+            .arg(codes)
+            .query_async(&mut self.conn)
+            .await?;
+
+        Ok(result
+            .iter()
+            .map(|value| value.as_deref().map(CouponIssue::from_value))
+            .collect())
+    }
+
+    // Only removes the given coupons, so that a batch claimed by another worker after this one's
+    // lock expired stays in flight until that worker archives it
+    pub async fn ack_in_flight(&mut self, set_id: i64, codes: &[String]) -> CacheResult<()> {
+        if codes.is_empty() {
+            return Ok(());
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for code in codes.iter() {
+            pipe.lrem(CouponSet::in_flight_key(set_id), 1, code)
+                .ignore();
+        }
+
+        pipe.hdel(CouponSet::issued_key(set_id), codes).ignore();
+
+        let _: () = pipe.query_async(&mut self.conn).await?;
+
+        Ok(())
+    }
+
+    pub async fn cached_coupons(&mut self, set_id: i64) -> CacheResult<CachedCoupons> {
+        let (available, used, in_flight, reserved): (
+            Vec<String>,
+            Vec<String>,
+            Vec<String>,
+            Vec<String>,
+        ) = redis::pipe()
+            .lrange(CouponSet::set_key(set_id), 0, -1)
+            .lrange(CouponSet::used_key(set_id), 0, -1)
+            .lrange(CouponSet::in_flight_key(set_id), 0, -1)
+            .lrange(CouponSet::reserved_key(set_id), 0, -1)
+            .query_async(&mut self.conn)
+            .await?;
+
+        let taken = used.into_iter().chain(in_flight).chain(reserved).collect();
+
+        Ok(CachedCoupons { available, taken })
+    }
+
+    pub async fn list_lengths(&mut self, set_ids: &[i64]) -> CacheResult<Vec<i64>> {
+        let mut pipe = redis::Pipeline::with_capacity(set_ids.len());
+
+        for set_id in set_ids.iter() {
+            pipe.llen(CouponSet::set_key(*set_id));
         }
 
         let lengths: Vec<i64> = pipe.query_async(&mut self.conn).await?;
 
-        // Assume that keys.len() == lengths.len()
-        let result = keys
+        Ok(lengths)
+    }
+
+    pub async fn set_status(&mut self) -> CacheResult<Vec<CouponSetCacheStatus>> {
+        let set_ids = self.active_set_ids().await?;
+
+        tracing::info!("found {} active sets", set_ids.len());
+
+        let lengths = self.list_lengths(&set_ids).await?;
+
+        let result = set_ids
             .into_iter()
             .zip(lengths)
-            .map(|(key, len)| CouponSetCacheStatus {
-                id: CouponSet::extract_set_id(&key),
+            .map(|(id, len)| CouponSetCacheStatus {
+                id,
                 total_coupons: len,
             })
             .collect();
@@ -3,6 +3,11 @@ use rslock::LockManager;
 
 use super::RedisConfig;
 
+// Held while a set is refilled, well over what a refill takes so that the filler and the refill on
+// a cache miss never work on the same set at once. Released by `unlock` as soon as the refill is
+// done.
+pub const REFILL_LOCK_TTL_MILLIS: usize = 30 * 1000;
+
 #[derive(Clone)]
 pub struct DistributedLock {
     lock_manager: LockManager,
@@ -21,8 +26,12 @@ impl DistributedLock {
         Self { lock_manager }
     }
 
-    pub async fn lock(&self, resource: &str) -> Option<Lock> {
-        let lock = self.lock_manager.lock(resource.as_bytes(), 100).await;
+    // The lock is released early by `unlock`, or expires after `ttl_millis` when the holder dies
+    pub async fn lock_for(&self, resource: &str, ttl_millis: usize) -> Option<Lock<'_>> {
+        let lock = self
+            .lock_manager
+            .lock(resource.as_bytes(), ttl_millis)
+            .await;
 
         lock.ok()
     }
@@ -1,9 +1,14 @@
+pub mod breaker;
 pub mod coupon;
 pub mod lock;
 
+use std::time::Duration;
+
+use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
-use redis::aio::MultiplexedConnection;
+use redis::aio::ConnectionManager;
+use redis::aio::ConnectionManagerConfig;
 use redis::ConnectionAddr;
 use redis::ConnectionInfo;
 use redis::RedisConnectionInfo;
@@ -18,10 +23,12 @@ struct RedisConfig {
     pub port: u16,
     #[serde(rename(deserialize = "cache_redis_database"))]
     pub database: i64,
+    #[serde(rename(deserialize = "cache_redis_timeout_milliseconds"))]
+    pub timeout_millis: u64,
 }
 
 #[tracing::instrument]
-pub async fn setup(env: &str) -> Result<(MultiplexedConnection, lock::DistributedLock)> {
+pub async fn setup(env: &str) -> Result<(ConnectionManager, lock::DistributedLock)> {
     tracing::info!("Setting up redis cache");
 
     let config = envy::from_env::<RedisConfig>().context("Failed to get env vars")?;
@@ -42,10 +49,29 @@ pub async fn setup(env: &str) -> Result<(MultiplexedConnection, lock::Distribute
     })
     .context("Failed to setup redis connection manager")?;
 
+    // Commands fail fast instead of hanging while redis is down, and the connection is
+    // re-established in the background once it is back
+    let timeout = Duration::from_millis(config.timeout_millis);
     let conn = client
-        .get_multiplexed_tokio_connection()
+        .get_connection_manager_with_config(
+            ConnectionManagerConfig::new()
+                .set_response_timeout(timeout)
+                .set_connection_timeout(timeout),
+        )
+        .await
+        .context("Failed to get redis connection manager")?;
+
+    let mut coupon_cache = coupon::CouponCache::new(conn.clone());
+
+    coupon_cache
+        .load_scripts()
+        .await
+        .map_err(|err| anyhow!("Failed to load redis scripts: {}", err))?;
+
+    coupon_cache
+        .register_existing_sets()
         .await
-        .context("Failed to get redis multiplexed connection")?;
+        .map_err(|err| anyhow!("Failed to register cached coupon sets: {}", err))?;
 
     let lock = lock::DistributedLock::new(&config);
 
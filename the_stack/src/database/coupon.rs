@@ -1,4 +1,6 @@
+use sqlx::postgres::PgListener;
 use sqlx::postgres::PgQueryResult;
+use sqlx::types::Json;
 use sqlx::Pool;
 use sqlx::Postgres;
 use uuid::Uuid;
@@ -6,17 +8,153 @@ use uuid::Uuid;
 use crate::api::dto::CreateCouponSetDto;
 use crate::error::database::DatabaseResult;
 use crate::model::coupon::Coupon;
+use crate::model::coupon::CouponIssue;
+use crate::model::coupon::CouponRecord;
+use crate::model::coupon::CouponReservation;
 use crate::model::coupon::CouponSet;
 use crate::model::coupon::CouponSetDatabaseStatus;
+use crate::model::coupon::CouponSetStatus;
+use crate::model::coupon::ExistingCoupon;
+use crate::model::coupon::IdempotencyClaim;
+
+// Channel of the `coupon_set_changed` trigger
+static COUPON_SET_CHANGED_CHANNEL: &str = "coupon_set_changed";
 
 static POP_COUPONS_QUERY: &str = r"
 WITH upd AS
     (UPDATE coupon SET used = true WHERE id IN
-            (SELECT id FROM coupon WHERE set_id = $1 AND used = false ORDER BY id LIMIT $2)
+            (SELECT id FROM coupon WHERE set_id = $1 AND used = false
+                AND (expires_at IS NULL OR expires_at > now())
+             ORDER BY id LIMIT $2
+             FOR UPDATE SKIP LOCKED)
     RETURNING *)
 SELECT * FROM upd
 ";
 
+// Hands coupons out without the cache, they go straight to the archive in the same statement since
+// there is no used list that the cleanup could move them from
+static HAND_OUT_COUPONS_QUERY: &str = r"
+WITH picked AS
+    (SELECT id FROM coupon WHERE set_id = $1 AND used = false
+        AND (expires_at IS NULL OR expires_at > now())
+     ORDER BY id LIMIT $2
+     FOR UPDATE SKIP LOCKED),
+moved AS
+    (DELETE FROM coupon WHERE id IN (SELECT id FROM picked)
+    RETURNING *)
+INSERT INTO coupon_used
+    (code, set_id, upload_job_id, created_at, expires_at, issued_at, issued_to, redeemed_at,
+        redemption_key, reserved_by, cancelled_by, redeemed_by)
+SELECT code, set_id, upload_job_id, created_at, expires_at, now(), $3, redeemed_at,
+    redemption_key, reserved_by, cancelled_by, redeemed_by
+FROM moved
+RETURNING code, set_id
+";
+
+static RESERVE_NEXT_COUPON_QUERY: &str = r"
+UPDATE coupon SET used = true, reservation_id = gen_random_uuid(),
+    reserved_until = now() + make_interval(secs => $2), reserved_by = $3
+WHERE id =
+    (SELECT id FROM coupon WHERE set_id = $1 AND used = false
+        AND (expires_at IS NULL OR expires_at > now())
+     ORDER BY id LIMIT 1
+     FOR UPDATE SKIP LOCKED)
+RETURNING *
+";
+
+static HAND_OUT_RESERVATION_QUERY: &str = r"
+WITH moved AS
+    (DELETE FROM coupon WHERE reservation_id = $1 AND reserved_by = $2 AND reserved_until > now()
+    RETURNING *)
+INSERT INTO coupon_used
+    (code, set_id, upload_job_id, created_at, expires_at, issued_at, issued_to, redeemed_at,
+        redemption_key, reserved_by, cancelled_by, redeemed_by)
+SELECT code, set_id, upload_job_id, created_at, expires_at, now(), $2, redeemed_at,
+    redemption_key, reserved_by, cancelled_by, redeemed_by
+FROM moved
+RETURNING code, set_id
+";
+
+// Expired reservations are only read here, they are cleared by `clear_reservations` once their
+// coupons are back in their sets
+static EXPIRED_RESERVATIONS_QUERY: &str = r"
+SELECT * FROM coupon WHERE reserved_until < now()
+ORDER BY reserved_until LIMIT $1
+";
+
+// `$2` holds who each coupon in `$1` was handed out to, null when that isn't known
+static ARCHIVE_COUPONS_QUERY: &str = r"
+WITH issued AS
+    (SELECT * FROM unnest($1::varchar[], $2::varchar[], $3::timestamptz[])
+        AS i (code, issued_to, issued_at)),
+moved AS
+    (DELETE FROM coupon WHERE code IN (SELECT code FROM issued)
+    RETURNING *)
+INSERT INTO coupon_used
+    (code, set_id, upload_job_id, created_at, expires_at, issued_at, issued_to, redeemed_at,
+        redemption_key, reserved_by, cancelled_by, redeemed_by)
+SELECT m.code, m.set_id, m.upload_job_id, m.created_at, m.expires_at,
+    coalesce(i.issued_at, m.issued_at, now()), i.issued_to, m.redeemed_at, m.redemption_key,
+    m.reserved_by, m.cancelled_by, m.redeemed_by
+FROM moved m JOIN issued i ON i.code = m.code
+ON CONFLICT (code) DO UPDATE SET set_id = excluded.set_id, upload_job_id = excluded.upload_job_id,
+    created_at = excluded.created_at, expires_at = excluded.expires_at,
+    issued_at = excluded.issued_at, issued_to = excluded.issued_to,
+    redeemed_at = excluded.redeemed_at, redemption_key = excluded.redemption_key,
+    reserved_by = excluded.reserved_by, cancelled_by = excluded.cancelled_by,
+    redeemed_by = excluded.redeemed_by
+RETURNING (xmax = 0) AS inserted
+";
+
+// Coupons that are not archived yet are still in the coupon table
+static GET_COUPON_QUERY: &str = r"
+SELECT code, set_id, used, created_at, expires_at, reservation_id, reserved_until, issued_at,
+    redeemed_at, redemption_key
+FROM coupon WHERE code = $1
+UNION ALL
+SELECT code, set_id, true, created_at, expires_at, null, null, issued_at, redeemed_at,
+    redemption_key
+FROM coupon_used WHERE code = $1
+";
+
+static REDEEM_COUPON_QUERY: &str = r"
+WITH archived AS
+    (UPDATE coupon_used SET redeemed_at = now(), redemption_key = $2, redeemed_by = $4
+        WHERE code = $1 AND redeemed_at IS NULL
+    RETURNING code, set_id, true AS used, created_at, expires_at, null::uuid AS reservation_id,
+        null::timestamptz AS reserved_until, issued_at, redeemed_at, redemption_key),
+live AS
+    (UPDATE coupon SET redeemed_at = now(), redemption_key = $2, redeemed_by = $4,
+            issued_at = coalesce(issued_at, $3, now())
+        WHERE code = $1 AND redeemed_at IS NULL
+    RETURNING code, set_id, used, created_at, expires_at, reservation_id, reserved_until,
+        issued_at, redeemed_at, redemption_key)
+SELECT * FROM archived
+UNION ALL
+SELECT * FROM live
+";
+
+static SET_STATUS_QUERY: &str = r"
+SELECT *,
+    (SELECT count(*) FROM coupon c WHERE c.set_id = s.id AND c.issued_at IS NULL)
+        AS total_coupons,
+    (SELECT count(*) FROM coupon c WHERE c.set_id = s.id AND c.issued_at IS NOT NULL)
+        + (SELECT count(*) FROM coupon_used cu WHERE cu.set_id = s.id)
+        AS total_issued,
+    (SELECT count(*) FROM coupon c WHERE c.set_id = s.id AND c.redeemed_at IS NOT NULL)
+        + (SELECT count(*) FROM coupon_used cu WHERE cu.set_id = s.id AND cu.redeemed_at IS NOT NULL)
+        AS total_redeemed
+FROM coupon_set s
+";
+
+// Coupons that expired while sitting in the cache, the ones already handed out are skipped by the
+// cache when removing them
+static EXPIRED_CACHED_COUPONS_QUERY: &str = r"
+SELECT * FROM coupon
+WHERE used = true AND reservation_id IS NULL AND issued_at IS NULL AND expires_at < now()
+ORDER BY id LIMIT $1
+";
+
 #[derive(Clone)]
 pub struct CouponRepository {
     conn: Pool<Postgres>,
@@ -27,27 +165,51 @@ impl CouponRepository {
         Self { conn }
     }
 
-    pub async fn batch_insert(&self, coupons: Vec<Coupon>) -> DatabaseResult<u64> {
-        let (ids, set_ids) =
+    pub async fn batch_insert(
+        &self,
+        coupons: Vec<Coupon>,
+        upload_job_id: Uuid,
+        expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> DatabaseResult<u64> {
+        let (codes, set_ids) =
             coupons
                 .into_iter()
-                .fold((vec![], vec![]), |(mut ids, mut set_ids), item| {
-                    ids.push(item.id);
+                .fold((vec![], vec![]), |(mut codes, mut set_ids), item| {
+                    codes.push(item.code);
                     set_ids.push(item.set_id);
-                    (ids, set_ids)
+                    (codes, set_ids)
                 });
 
         let result: PgQueryResult = sqlx::query(
-            "insert into coupon(id, set_id) select * from unnest($1::uuid[], $2::int8[])",
+            r"insert into coupon(code, set_id, upload_job_id, expires_at)
+                select *, $3, $4 from unnest($1::varchar[], $2::int8[]) as u(code, set_id)
+                where not exists (select 1 from coupon_used cu where cu.code = u.code)
+                on conflict (code) do nothing",
         )
-        .bind(&ids)
+        .bind(&codes)
         .bind(&set_ids)
+        .bind(upload_job_id)
+        .bind(expires_at)
         .execute(&self.conn)
         .await?;
 
         Ok(result.rows_affected())
     }
 
+    pub async fn existing_coupons(&self, codes: &[String]) -> DatabaseResult<Vec<ExistingCoupon>> {
+        let result = sqlx::query_as(
+            r"select code, set_id, upload_job_id from coupon where code = any($1::varchar[])
+                union all
+                select code, set_id, upload_job_id from coupon_used
+                where code = any($1::varchar[])",
+        )
+        .bind(codes)
+        .fetch_all(&self.conn)
+        .await?;
+
+        Ok(result)
+    }
+
     pub async fn pop_coupons(&self, set_id: i64, limit: i64) -> DatabaseResult<Vec<Coupon>> {
         let result = sqlx::query_as(POP_COUPONS_QUERY)
             .bind(set_id)
@@ -58,38 +220,593 @@ impl CouponRepository {
         Ok(result)
     }
 
-    pub async fn delete_coupons(&self, coupons: &[Uuid]) -> DatabaseResult<u64> {
+    pub async fn hand_out_coupons(
+        &self,
+        set_id: i64,
+        limit: i64,
+        issued_to: &str,
+    ) -> DatabaseResult<Vec<Coupon>> {
+        let result = sqlx::query_as(HAND_OUT_COUPONS_QUERY)
+            .bind(set_id)
+            .bind(limit)
+            .bind(issued_to)
+            .fetch_all(&self.conn)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn reserve_coupon(
+        &self,
+        code: &str,
+        ttl_seconds: i64,
+        reserved_by: &str,
+    ) -> DatabaseResult<CouponReservation> {
+        let result = sqlx::query_as(
+            r"update coupon set reservation_id = gen_random_uuid(),
+                reserved_until = now() + make_interval(secs => $2), reserved_by = $3
+                where code = $1 returning *",
+        )
+        .bind(code)
+        .bind(ttl_seconds as f64)
+        .bind(reserved_by)
+        .fetch_one(&self.conn)
+        .await?;
+
+        Ok(result)
+    }
+
+    // Reserves the next available coupon of the set without going through the cache
+    pub async fn reserve_next_coupon(
+        &self,
+        set_id: i64,
+        ttl_seconds: i64,
+        reserved_by: &str,
+    ) -> DatabaseResult<CouponReservation> {
+        let result = sqlx::query_as(RESERVE_NEXT_COUPON_QUERY)
+            .bind(set_id)
+            .bind(ttl_seconds as f64)
+            .bind(reserved_by)
+            .fetch_one(&self.conn)
+            .await?;
+
+        Ok(result)
+    }
+
+    // Confirms a reservation made by `reserve_next_coupon`, archiving its coupon right away. Only
+    // the subject that reserved the coupon can confirm it.
+    pub async fn hand_out_reservation(
+        &self,
+        reservation_id: Uuid,
+        issued_to: &str,
+    ) -> DatabaseResult<Coupon> {
+        let result = sqlx::query_as(HAND_OUT_RESERVATION_QUERY)
+            .bind(reservation_id)
+            .bind(issued_to)
+            .fetch_one(&self.conn)
+            .await?;
+
+        Ok(result)
+    }
+
+    // Cancels a reservation made by `reserve_next_coupon`, the coupon is available again
+    pub async fn release_reservation(
+        &self,
+        reservation_id: Uuid,
+        reserved_by: &str,
+    ) -> DatabaseResult<Coupon> {
+        let result = sqlx::query_as(
+            r"update coupon set used = false, reservation_id = null, reserved_until = null,
+                    reserved_by = null, cancelled_by = $2
+                where reservation_id = $1 and reserved_by = $2 and reserved_until > now()
+                returning *",
+        )
+        .bind(reservation_id)
+        .bind(reserved_by)
+        .fetch_one(&self.conn)
+        .await?;
+
+        Ok(result)
+    }
+
+    // Reservations of another subject are not found
+    pub async fn confirm_reservation(
+        &self,
+        reservation_id: Uuid,
+        reserved_by: &str,
+    ) -> DatabaseResult<Coupon> {
+        let result = sqlx::query_as(
+            r"update coupon set reservation_id = null, reserved_until = null
+                where reservation_id = $1 and reserved_by = $2 and reserved_until > now()
+                returning *",
+        )
+        .bind(reservation_id)
+        .bind(reserved_by)
+        .fetch_one(&self.conn)
+        .await?;
+
+        Ok(result)
+    }
+
+    // Expired reservations belong to the sweeper, so that a cancel and the sweeper never both give
+    // the same coupon back
+    pub async fn cancel_reservation(
+        &self,
+        reservation_id: Uuid,
+        reserved_by: &str,
+    ) -> DatabaseResult<Coupon> {
+        let result = sqlx::query_as(
+            r"update coupon set reservation_id = null, reserved_until = null, reserved_by = null,
+                    cancelled_by = $2
+                where reservation_id = $1 and reserved_by = $2 and reserved_until > now()
+                returning *",
+        )
+        .bind(reservation_id)
+        .bind(reserved_by)
+        .fetch_one(&self.conn)
+        .await?;
+
+        Ok(result)
+    }
+
+    pub async fn expired_reservations(&self, limit: i64) -> DatabaseResult<Vec<CouponReservation>> {
+        let result = sqlx::query_as(EXPIRED_RESERVATIONS_QUERY)
+            .bind(limit)
+            .fetch_all(&self.conn)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn clear_reservations(&self, reservation_ids: &[Uuid]) -> DatabaseResult<u64> {
+        if reservation_ids.is_empty() {
+            return Ok(0);
+        }
+
+        let result = sqlx::query(
+            r"update coupon set reservation_id = null, reserved_until = null, reserved_by = null
+                where reservation_id = any($1::uuid[]) and reserved_until < now()",
+        )
+        .bind(reservation_ids)
+        .execute(&self.conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn sets_with_available_coupons(&self) -> DatabaseResult<Vec<i64>> {
+        let result = sqlx::query_scalar(
+            r"select distinct c.set_id from coupon c join coupon_set s on s.id = c.set_id
+                where c.used = false
+                and (c.expires_at is null or c.expires_at > now())
+                and (s.ends_at is null or s.ends_at > now())
+                and s.status = 'active'
+                order by 1",
+        )
+        .fetch_all(&self.conn)
+        .await?;
+
+        Ok(result)
+    }
+
+    // Sets with coupons that were taken from the database for the cache and not handed out yet
+    pub async fn cached_set_ids(&self) -> DatabaseResult<Vec<i64>> {
+        let result = sqlx::query_scalar(
+            r"select distinct set_id from coupon
+                where used = true and reservation_id is null and issued_at is null
+                order by 1",
+        )
+        .fetch_all(&self.conn)
+        .await?;
+
+        Ok(result)
+    }
+
+    // Codes the database expects to find in one of the cache lists of the set
+    pub async fn cached_coupons(&self, set_id: i64) -> DatabaseResult<Vec<String>> {
+        let result = sqlx::query_scalar(
+            r"select code from coupon
+                where set_id = $1 and used = true and reservation_id is null and issued_at is null",
+        )
+        .bind(set_id)
+        .fetch_all(&self.conn)
+        .await?;
+
+        Ok(result)
+    }
+
+    // Ended sets that still have coupons waiting in the cache, purged sets have none left
+    pub async fn expired_set_ids(&self) -> DatabaseResult<Vec<i64>> {
+        let result = sqlx::query_scalar(
+            r"select s.id from coupon_set s where s.ends_at < now()
+                and exists (select 1 from coupon c where c.set_id = s.id and c.used = true
+                    and c.reservation_id is null and c.issued_at is null)",
+        )
+        .fetch_all(&self.conn)
+        .await?;
+
+        Ok(result)
+    }
+
+    pub async fn expired_cached_coupons(&self, limit: i64) -> DatabaseResult<Vec<Coupon>> {
+        let result = sqlx::query_as(EXPIRED_CACHED_COUPONS_QUERY)
+            .bind(limit)
+            .fetch_all(&self.conn)
+            .await?;
+
+        Ok(result)
+    }
+
+    // Marks coupons as no longer being in the cache
+    pub async fn release_coupons(&self, coupons: &[String]) -> DatabaseResult<u64> {
         if coupons.is_empty() {
             return Ok(0);
         }
 
-        let result =
-            sqlx::query("delete from coupon where id in (select * from unnest($1::uuid[]))")
-                .bind(coupons)
-                .execute(&self.conn)
+        let result = sqlx::query(
+            "update coupon set used = false where code in (select * from unnest($1::varchar[]))",
+        )
+        .bind(coupons)
+        .execute(&self.conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    // Moves handed out coupons into the archive, delete and insert are one statement so that a
+    // coupon is never in both tables or in neither
+    pub async fn archive_coupons(
+        &self,
+        coupons: &[String],
+        issued: &[Option<CouponIssue>],
+    ) -> DatabaseResult<u64> {
+        if coupons.is_empty() {
+            return Ok(0);
+        }
+
+        let (issued_to, issued_at): (
+            Vec<Option<String>>,
+            Vec<Option<chrono::DateTime<chrono::Utc>>>,
+        ) = issued
+            .iter()
+            .map(|issue| match issue {
+                Some(issue) => (Some(issue.issued_to.clone()), issue.issued_at),
+                None => (None, None),
+            })
+            .unzip();
+
+        let inserted: Vec<bool> = sqlx::query_scalar(ARCHIVE_COUPONS_QUERY)
+            .bind(coupons)
+            .bind(issued_to)
+            .bind(issued_at)
+            .fetch_all(&self.conn)
+            .await?;
+
+        // A code that was uploaded again while its earlier coupon was being archived. The coupon
+        // that was just handed out replaces the earlier archive entry instead of being dropped.
+        let replaced = inserted.iter().filter(|inserted| !**inserted).count();
+
+        if replaced > 0 {
+            tracing::warn!(
+                replaced,
+                "archived coupons replaced earlier archive entries"
+            );
+        }
+
+        Ok(inserted.len() as u64)
+    }
+
+    // Deletes archived coupons that were issued before the cutoff, at most `limit` at a time
+    pub async fn purge_archived_coupons(
+        &self,
+        issued_before: chrono::DateTime<chrono::Utc>,
+        limit: i64,
+    ) -> DatabaseResult<u64> {
+        let result = sqlx::query(
+            r"delete from coupon_used where code in
+                (select code from coupon_used where issued_at < $1 limit $2)",
+        )
+        .bind(issued_before)
+        .bind(limit)
+        .execute(&self.conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    // Only succeeds once per coupon, a coupon that was handed out but not recorded yet is marked as
+    // issued as well, at `issued_at` when the store knows it
+    pub async fn redeem_coupon(
+        &self,
+        code: &str,
+        redemption_key: Option<&str>,
+        issued_at: Option<chrono::DateTime<chrono::Utc>>,
+        redeemed_by: &str,
+    ) -> DatabaseResult<CouponRecord> {
+        let result = sqlx::query_as(REDEEM_COUPON_QUERY)
+            .bind(code)
+            .bind(redemption_key)
+            .bind(issued_at)
+            .bind(redeemed_by)
+            .fetch_one(&self.conn)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn archived_coupon_issue(&self, code: &str) -> DatabaseResult<Option<CouponIssue>> {
+        let result: Option<(Option<String>, chrono::DateTime<chrono::Utc>)> =
+            sqlx::query_as("select issued_to, issued_at from coupon_used where code = $1")
+                .bind(code)
+                .fetch_optional(&self.conn)
                 .await?;
 
+        Ok(result.map(|(issued_to, issued_at)| CouponIssue {
+            issued_to: issued_to.unwrap_or_default(),
+            issued_at: Some(issued_at),
+        }))
+    }
+
+    pub async fn get_coupon(&self, code: &str) -> DatabaseResult<CouponRecord> {
+        let result = sqlx::query_as(GET_COUPON_QUERY)
+            .bind(code)
+            .fetch_one(&self.conn)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn claim_idempotency_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+        pending_seconds: u64,
+    ) -> DatabaseResult<IdempotencyClaim> {
+        sqlx::query(
+            r"delete from coupon_idempotency
+                where set_id = $1 and subject = $2 and key = $3 and expires_at <= now()",
+        )
+        .bind(set_id)
+        .bind(subject)
+        .bind(key)
+        .execute(&self.conn)
+        .await?;
+
+        let inserted = sqlx::query(
+            r"insert into coupon_idempotency(set_id, subject, key, expires_at)
+                values ($1, $2, $3, now() + make_interval(secs => $4))
+                on conflict do nothing",
+        )
+        .bind(set_id)
+        .bind(subject)
+        .bind(key)
+        .bind(pending_seconds as f64)
+        .execute(&self.conn)
+        .await?
+        .rows_affected();
+
+        if inserted > 0 {
+            return Ok(IdempotencyClaim::New);
+        }
+
+        let code: Option<Option<String>> = sqlx::query_scalar(
+            "select code from coupon_idempotency where set_id = $1 and subject = $2 and key = $3",
+        )
+        .bind(set_id)
+        .bind(subject)
+        .bind(key)
+        .fetch_optional(&self.conn)
+        .await?;
+
+        // A key that expired in between is treated as pending, the client retries
+        let result = match code.flatten() {
+            Some(code) => IdempotencyClaim::Replay(code),
+            None => IdempotencyClaim::Pending,
+        };
+
+        Ok(result)
+    }
+
+    pub async fn store_idempotency_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+        code: &str,
+        window_seconds: u64,
+    ) -> DatabaseResult<()> {
+        sqlx::query(
+            r"update coupon_idempotency set code = $4, expires_at = now() + make_interval(secs => $5)
+                where set_id = $1 and subject = $2 and key = $3",
+        )
+        .bind(set_id)
+        .bind(subject)
+        .bind(key)
+        .bind(code)
+        .bind(window_seconds as f64)
+        .execute(&self.conn)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn release_idempotency_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+    ) -> DatabaseResult<()> {
+        sqlx::query(
+            "delete from coupon_idempotency where set_id = $1 and subject = $2 and key = $3",
+        )
+        .bind(set_id)
+        .bind(subject)
+        .bind(key)
+        .execute(&self.conn)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn purge_idempotency_keys(&self) -> DatabaseResult<u64> {
+        let result = sqlx::query("delete from coupon_idempotency where expires_at <= now()")
+            .execute(&self.conn)
+            .await?;
+
         Ok(result.rows_affected())
     }
 
+    // Same claim as idempotency keys, but assignments don't expire
+    pub async fn claim_assignment(
+        &self,
+        set_id: i64,
+        subject: &str,
+    ) -> DatabaseResult<IdempotencyClaim> {
+        // A first pop that never finished, e.g. the instance died, doesn't block the subject forever
+        sqlx::query(
+            r"delete from coupon_assignment where set_id = $1 and subject = $2 and code is null
+                and assigned_at < now() - interval '1 minute'",
+        )
+        .bind(set_id)
+        .bind(subject)
+        .execute(&self.conn)
+        .await?;
+
+        let inserted = sqlx::query(
+            r"insert into coupon_assignment(set_id, subject) values ($1, $2)
+                on conflict do nothing",
+        )
+        .bind(set_id)
+        .bind(subject)
+        .execute(&self.conn)
+        .await?
+        .rows_affected();
+
+        if inserted > 0 {
+            return Ok(IdempotencyClaim::New);
+        }
+
+        let code: Option<Option<String>> = sqlx::query_scalar(
+            "select code from coupon_assignment where set_id = $1 and subject = $2",
+        )
+        .bind(set_id)
+        .bind(subject)
+        .fetch_optional(&self.conn)
+        .await?;
+
+        let result = match code.flatten() {
+            Some(code) => IdempotencyClaim::Replay(code),
+            None => IdempotencyClaim::Pending,
+        };
+
+        Ok(result)
+    }
+
+    pub async fn store_assignment(
+        &self,
+        set_id: i64,
+        subject: &str,
+        code: &str,
+    ) -> DatabaseResult<()> {
+        sqlx::query(
+            r"update coupon_assignment set code = $3, assigned_at = now()
+                where set_id = $1 and subject = $2",
+        )
+        .bind(set_id)
+        .bind(subject)
+        .bind(code)
+        .execute(&self.conn)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn release_assignment(&self, set_id: i64, subject: &str) -> DatabaseResult<()> {
+        sqlx::query(
+            "delete from coupon_assignment where set_id = $1 and subject = $2 and code is null",
+        )
+        .bind(set_id)
+        .bind(subject)
+        .execute(&self.conn)
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn create_set(&self, create_dto: CreateCouponSetDto) -> DatabaseResult<CouponSet> {
         let result = sqlx::query_as(
-            "with add as (insert into coupon_set (name) values ($1) returning *) select * from add",
+            r"with add as (insert into coupon_set (name, starts_at, ends_at, code_format, sticky)
+                values ($1, $2, $3, $4, $5) returning *) select * from add",
         )
         .bind(create_dto.name)
+        .bind(create_dto.starts_at)
+        .bind(create_dto.ends_at)
+        .bind(Json(create_dto.code_format))
+        .bind(create_dto.sticky)
         .fetch_one(&self.conn)
         .await?;
 
         Ok(result)
     }
 
-    pub async fn set_status(&self) -> DatabaseResult<Vec<CouponSetDatabaseStatus>> {
-        let result = sqlx::query_as(
-            "select *, (select count(*) from coupon c where c.set_id = s.id) as total_coupons from coupon_set s",
+    pub async fn get_set(&self, set_id: i64) -> DatabaseResult<CouponSet> {
+        let result = sqlx::query_as("select * from coupon_set where id = $1")
+            .bind(set_id)
+            .fetch_one(&self.conn)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn update_set_status(
+        &self,
+        set_id: i64,
+        status: CouponSetStatus,
+    ) -> DatabaseResult<CouponSet> {
+        let result = sqlx::query_as("update coupon_set set status = $2 where id = $1 returning *")
+            .bind(set_id)
+            .bind(status)
+            .fetch_one(&self.conn)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn delete_set_coupons(&self, set_id: i64, limit: i64) -> DatabaseResult<u64> {
+        let result = sqlx::query(
+            "delete from coupon where id in (select id from coupon where set_id = $1 limit $2)",
         )
-        .fetch_all(&self.conn)
+        .bind(set_id)
+        .bind(limit)
+        .execute(&self.conn)
         .await?;
 
+        Ok(result.rows_affected())
+    }
+
+    pub async fn delete_set(&self, set_id: i64) -> DatabaseResult<u64> {
+        let result = sqlx::query("delete from coupon_set where id = $1")
+            .bind(set_id)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    // Notified with the set id whenever a set changes its status or is deleted
+    pub async fn listen_set_changes(&self) -> DatabaseResult<PgListener> {
+        let mut listener = PgListener::connect_with(&self.conn).await?;
+        listener.listen(COUPON_SET_CHANGED_CHANNEL).await?;
+
+        Ok(listener)
+    }
+
+    pub async fn set_status(&self) -> DatabaseResult<Vec<CouponSetDatabaseStatus>> {
+        let result = sqlx::query_as(SET_STATUS_QUERY)
+            .fetch_all(&self.conn)
+            .await?;
+
         Ok(result)
     }
 }
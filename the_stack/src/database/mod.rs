@@ -1,4 +1,5 @@
 pub mod coupon;
+pub mod upload;
 pub mod userlogin;
 
 use anyhow::Context;
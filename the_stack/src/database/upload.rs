@@ -0,0 +1,107 @@
+use sqlx::Pool;
+use sqlx::Postgres;
+use uuid::Uuid;
+
+use crate::error::database::DatabaseResult;
+use crate::model::upload::UploadJob;
+use crate::model::upload::UploadJobCounts;
+use crate::model::upload::UploadJobKind;
+use crate::model::upload::UploadJobState;
+use crate::model::upload::UploadRejection;
+
+#[derive(Clone)]
+pub struct UploadJobRepository {
+    conn: Pool<Postgres>,
+}
+
+impl UploadJobRepository {
+    pub fn new(conn: Pool<Postgres>) -> Self {
+        Self { conn }
+    }
+
+    pub async fn create(&self, set_id: i64, kind: UploadJobKind) -> DatabaseResult<UploadJob> {
+        let result = sqlx::query_as(
+            r"with add as (insert into upload_job (set_id, kind) values ($1, $2) returning *)
+                select * from add",
+        )
+        .bind(set_id)
+        .bind(kind)
+        .fetch_one(&self.conn)
+        .await?;
+
+        Ok(result)
+    }
+
+    pub async fn get(&self, id: Uuid) -> DatabaseResult<UploadJob> {
+        let result = sqlx::query_as("select * from upload_job where id = $1")
+            .bind(id)
+            .fetch_one(&self.conn)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn update(
+        &self,
+        id: Uuid,
+        state: UploadJobState,
+        counts: UploadJobCounts,
+        error: Option<String>,
+    ) -> DatabaseResult<u64> {
+        let result = sqlx::query(
+            r"update upload_job set state = $2, parsed = $3, rejected = $4, inserted = $5,
+                error = $6, updated_at = now() where id = $1",
+        )
+        .bind(id)
+        .bind(state)
+        .bind(counts.parsed)
+        .bind(counts.rejected)
+        .bind(counts.inserted)
+        .bind(error)
+        .execute(&self.conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn add_rejections(
+        &self,
+        job_id: Uuid,
+        rejections: Vec<UploadRejection>,
+    ) -> DatabaseResult<u64> {
+        let (codes, reasons, existing_set_ids) = rejections.into_iter().fold(
+            (vec![], vec![], vec![]),
+            |(mut codes, mut reasons, mut existing_set_ids), item| {
+                codes.push(item.code);
+                reasons.push(item.reason);
+                existing_set_ids.push(item.existing_set_id);
+                (codes, reasons, existing_set_ids)
+            },
+        );
+
+        let result = sqlx::query(
+            r"insert into upload_rejection(job_id, code, reason, existing_set_id)
+                select $1, * from unnest($2::varchar[], $3::varchar[], $4::int8[])",
+        )
+        .bind(job_id)
+        .bind(&codes)
+        .bind(&reasons)
+        .bind(&existing_set_ids)
+        .execute(&self.conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn rejections(&self, job_id: Uuid) -> DatabaseResult<Vec<UploadRejection>> {
+        let result = sqlx::query_as(
+            r"select code, reason, existing_set_id from upload_rejection
+                where job_id = $1 order by id",
+        )
+        .bind(job_id)
+        .fetch_all(&self.conn)
+        .await?;
+
+        Ok(result)
+    }
+}
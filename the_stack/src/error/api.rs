@@ -16,6 +16,10 @@ pub enum ApiError {
     Conflict(anyhow::Error, String),
     NotFound(String),
     BadRequest(String),
+    Forbidden(String),
+    Unavailable(String),
+    Gone(String),
+    ServiceUnavailable(String),
     Unauthorized {
         message: String,
         error: Option<anyhow::Error>,
@@ -34,12 +38,24 @@ impl ApiError {
 #[derive(Serialize)]
 struct ResponseBody {
     message: String,
+    // Machine readable reason for errors about the state of a set or coupon
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
 }
 
 impl ResponseBody {
     pub fn from(message: &str) -> String {
         serde_json::to_string(&Self {
             message: message.to_string(),
+            code: None,
+        })
+        .unwrap_or_default()
+    }
+
+    pub fn with_code(message: &str, code: &'static str) -> String {
+        serde_json::to_string(&Self {
+            message: message.to_string(),
+            code: Some(code),
         })
         .unwrap_or_default()
     }
@@ -51,6 +67,12 @@ impl Display for ApiError {
             ApiError::Internal(err) => write!(f, "ApiError: Internal: {}", err),
             ApiError::NotFound(message) => write!(f, "ApiError: NotFound: {}", message),
             ApiError::BadRequest(message) => write!(f, "ApiError: BadRequest: {}", message),
+            ApiError::Forbidden(message) => write!(f, "ApiError: Forbidden: {}", message),
+            ApiError::Unavailable(message) => write!(f, "ApiError: Unavailable: {}", message),
+            ApiError::Gone(message) => write!(f, "ApiError: Gone: {}", message),
+            ApiError::ServiceUnavailable(message) => {
+                write!(f, "ApiError: ServiceUnavailable: {}", message)
+            }
             ApiError::Unauthorized { message, error } => {
                 write!(
                     f,
@@ -92,6 +114,39 @@ impl IntoResponse for ApiError {
                 )
                     .into_response()
             }
+            ApiError::Forbidden(message) => {
+                tracing::info!(message, "Forbidden");
+
+                (StatusCode::FORBIDDEN, ResponseBody::from(message.as_str())).into_response()
+            }
+            ApiError::Unavailable(message) => {
+                tracing::info!(message, "Unavailable");
+
+                // Not 409, that is taken by conflicts on existing resources
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    ResponseBody::with_code(message.as_str(), "unavailable"),
+                )
+                    .into_response()
+            }
+            ApiError::Gone(message) => {
+                tracing::info!(message, "Gone");
+
+                (
+                    StatusCode::GONE,
+                    ResponseBody::with_code(message.as_str(), "gone"),
+                )
+                    .into_response()
+            }
+            ApiError::ServiceUnavailable(message) => {
+                tracing::warn!(message, "Service Unavailable");
+
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    ResponseBody::from(message.as_str()),
+                )
+                    .into_response()
+            }
             ApiError::Unauthorized { message, error } => {
                 let error = error.unwrap_or(anyhow!("Error")).to_string();
                 tracing::info!(message, error, "Unauthorized");
@@ -120,6 +175,10 @@ impl From<ServiceError> for ApiError {
             ServiceError::NotFound => ApiError::NotFound("Not Found".to_string()),
             ServiceError::Internal(err) => ApiError::Internal(err),
             ServiceError::Unauthorized => ApiError::default_unauthorized(),
+            ServiceError::Unavailable(message) => ApiError::Unavailable(message),
+            ServiceError::Gone(message) => ApiError::Gone(message),
+            ServiceError::Degraded(message) => ApiError::ServiceUnavailable(message),
+            ServiceError::BadRequest(message) => ApiError::BadRequest(message),
             ServiceError::Conflict(err, conflict) => ApiError::Conflict(err, conflict),
         }
     }
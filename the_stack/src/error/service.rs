@@ -10,6 +10,12 @@ pub enum ServiceError {
     NotFound,
     Unauthorized,
     Conflict(anyhow::Error, String),
+    // Temporarily can't be handed out or redeemed, e.g. a paused set
+    Unavailable(String),
+    // Can't be handed out or redeemed anymore, e.g. an ended campaign
+    Gone(String),
+    Degraded(String),
+    BadRequest(String),
     Internal(anyhow::Error),
 }
 
@@ -19,6 +25,16 @@ impl Display for ServiceError {
             ServiceError::NotFound => write!(f, "ServiceError: Not Found"),
             ServiceError::Internal(err) => write!(f, "ServiceError: Internal: {}", err),
             ServiceError::Unauthorized => write!(f, "ServiceError: Unauthorized"),
+            ServiceError::Unavailable(message) => {
+                write!(f, "ServiceError: Unavailable: {}", message)
+            }
+            ServiceError::Gone(message) => write!(f, "ServiceError: Gone: {}", message),
+            ServiceError::Degraded(message) => {
+                write!(f, "ServiceError: Degraded: {}", message)
+            }
+            ServiceError::BadRequest(message) => {
+                write!(f, "ServiceError: BadRequest: {}", message)
+            }
             ServiceError::Conflict(err, _) => {
                 write!(f, "ServiceError: Conflict: {}", err)
             }
@@ -1,50 +1,168 @@
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 
 use anyhow::Context;
 use anyhow::Result;
-use redis::aio::MultiplexedConnection;
+use redis::aio::ConnectionManager;
 use serde::Deserialize;
 use sqlx::Pool;
 use sqlx::Postgres;
 
+use crate::cache::coupon::CouponCache;
+use crate::cache::lock::DistributedLock;
+use crate::cache::lock::REFILL_LOCK_TTL_MILLIS;
+use crate::database::coupon::CouponRepository;
 use crate::metrics::Metrics;
+use crate::service::BatchInsertConfig;
 
 #[derive(Deserialize, Debug)]
-pub struct WorkerConfig {
-    #[serde(rename(deserialize = "worker_timeout_seconds"))]
-    pub timeout_seconds: u64,
+pub struct FillerConfig {
+    #[serde(rename(deserialize = "filler_low_water_mark"))]
+    pub low_water_mark: i64,
 }
 
 #[tracing::instrument(skip_all)]
 pub fn setup(
-    cache: MultiplexedConnection,
+    cache: ConnectionManager,
     db: Pool<Postgres>,
     metrics: Metrics,
-) -> Result<Arc<Mutex<u64>>> {
-    tracing::info!("Setting up worker");
+    lock: DistributedLock,
+    batch_config: BatchInsertConfig,
+    timeout: Arc<Mutex<u64>>,
+) -> Result<()> {
+    tracing::info!("Setting up filler");
 
-    let config = envy::from_env::<WorkerConfig>().context("Failed to get env vars")?;
+    let config = envy::from_env::<FillerConfig>().context("Failed to get env vars")?;
 
-    let timeout = Arc::new(Mutex::new(config.timeout_seconds));
+    tokio::task::spawn(async move {
+        filler_worker(cache, db, metrics, lock, batch_config, config, timeout).await;
+    });
 
-    {
-        let timeout = timeout.clone();
-        tokio::task::spawn(async move {
-            filler_worker(cache, db, metrics, timeout).await;
-        });
-    }
-
-    Ok(timeout)
+    Ok(())
 }
 
 #[tracing::instrument(skip_all)]
 pub async fn filler_worker(
-    _cache: MultiplexedConnection,
-    _db: Pool<Postgres>,
-    _metrics: Metrics,
-    _timeout: Arc<Mutex<u64>>,
+    cache: ConnectionManager,
+    db: Pool<Postgres>,
+    metrics: Metrics,
+    lock: DistributedLock,
+    batch_config: BatchInsertConfig,
+    config: FillerConfig,
+    timeout: Arc<Mutex<u64>>,
 ) {
-    // Fills up the redis cache before they run out of coupons
-    todo!()
+    tracing::info!("starting filler worker loop");
+
+    let coupon_database = CouponRepository::new(db);
+    let mut coupon_cache = CouponCache::new(cache);
+
+    loop {
+        tracing::info!("checking coupon sets for refill");
+
+        let set_ids = match coupon_database.sets_with_available_coupons().await {
+            Ok(result) => result,
+            Err(error) => {
+                let error = error.to_string();
+                tracing::error!(error, "error getting coupon sets to refill");
+                vec![]
+            }
+        };
+
+        let lengths = match coupon_cache.list_lengths(&set_ids).await {
+            Ok(result) => result,
+            Err(error) => {
+                let error = error.to_string();
+                tracing::error!(error, "error getting cache length of coupon sets");
+                vec![]
+            }
+        };
+
+        // Assume that set_ids.len() == lengths.len()
+        let to_refill = set_ids
+            .into_iter()
+            .zip(lengths)
+            .filter(|(_, len)| *len < config.low_water_mark)
+            .map(|(set_id, _)| set_id)
+            .collect::<Vec<i64>>();
+
+        if !to_refill.is_empty() {
+            // Only inc when we are actually filling up something
+            metrics.job_filler.inc();
+
+            tracing::info!("refilling {} coupon sets", to_refill.len());
+        }
+
+        for set_id in to_refill.into_iter() {
+            // Same lock as the refill done by the coupon service on a cache miss
+            let Some(set_lock) = lock
+                .lock_for(
+                    &format!("{}{}", batch_config.lock_prefix, set_id),
+                    REFILL_LOCK_TTL_MILLIS,
+                )
+                .await
+            else {
+                tracing::info!(set_id, "coupon set is already being refilled");
+                continue;
+            };
+
+            let result = refill(
+                &coupon_database,
+                &mut coupon_cache,
+                set_id,
+                batch_config.insert_total,
+            )
+            .await;
+
+            lock.unlock(set_lock).await;
+
+            match result {
+                Ok(total) => {
+                    metrics.filler_refills.inc();
+                    metrics.filler_coupons.inc_by(total as f64);
+
+                    tracing::info!(set_id, total, "coupon set refilled");
+                }
+                Err(error) => tracing::error!(set_id, error, "error when refilling coupon set"),
+            }
+        }
+
+        let timeout = *timeout.lock().expect("Could not acquire lock for timeout");
+        tracing::info!(timeout, "filling up finished and now waiting");
+        tokio::time::sleep(Duration::from_secs(timeout)).await;
+    }
+}
+
+// Rows are marked as used before they are pushed, so they are released again when the push fails.
+// Otherwise they would be in neither the database nor the cache.
+async fn refill(
+    coupon_database: &CouponRepository,
+    coupon_cache: &mut CouponCache,
+    set_id: i64,
+    total: i64,
+) -> Result<usize, String> {
+    let coupons = coupon_database
+        .pop_coupons(set_id, total)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    if let Err(error) = coupon_cache.batch_insert(set_id, &coupons).await {
+        let codes = coupons
+            .into_iter()
+            .map(|coupon| coupon.code)
+            .collect::<Vec<String>>();
+
+        if let Err(error) = coupon_database.release_coupons(&codes).await {
+            let error = error.to_string();
+            tracing::error!(
+                set_id,
+                error,
+                "error releasing coupons that were not refilled"
+            );
+        }
+
+        return Err(error.to_string());
+    }
+
+    Ok(coupons.len())
 }
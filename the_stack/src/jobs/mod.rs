@@ -1,2 +1,6 @@
 pub mod filler;
+pub mod purger;
+pub mod reconcile;
+pub mod retention;
+pub mod sweeper;
 pub mod worker;
@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use redis::aio::ConnectionManager;
+use sqlx::Pool;
+use sqlx::Postgres;
+
+use crate::cache::coupon::CouponCache;
+use crate::database::coupon::CouponRepository;
+use crate::metrics::Metrics;
+use crate::model::coupon::Coupon;
+
+const PURGE_LIMIT: i64 = 10000;
+
+#[tracing::instrument(skip_all)]
+pub fn setup(
+    cache: ConnectionManager,
+    db: Pool<Postgres>,
+    metrics: Metrics,
+    timeout: Arc<Mutex<u64>>,
+) -> Result<()> {
+    tracing::info!("Setting up purger");
+
+    tokio::task::spawn(async move {
+        purger_worker(cache, db, metrics, timeout).await;
+    });
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn purger_worker(
+    cache: ConnectionManager,
+    db: Pool<Postgres>,
+    metrics: Metrics,
+    timeout: Arc<Mutex<u64>>,
+) {
+    tracing::info!("starting purger worker loop");
+
+    let coupon_database = CouponRepository::new(db);
+    let mut coupon_cache = CouponCache::new(cache);
+
+    loop {
+        tracing::info!("purging expired coupon sets and coupons");
+
+        metrics.job_purger.inc();
+
+        match coupon_database.expired_set_ids().await {
+            Ok(set_ids) => {
+                for set_id in set_ids.into_iter() {
+                    match purge_set(&coupon_database, &mut coupon_cache, set_id).await {
+                        Ok(rows_affected) => {
+                            metrics.purged_coupons.inc_by(rows_affected as f64);
+                            tracing::info!(set_id, rows_affected, "expired coupon set purged");
+                        }
+                        Err(error) => {
+                            tracing::error!(set_id, error, "error purging expired coupon set")
+                        }
+                    }
+                }
+            }
+            Err(error) => {
+                let error = error.to_string();
+                tracing::error!(error, "error getting expired coupon sets");
+            }
+        }
+
+        let expired = match coupon_database.expired_cached_coupons(PURGE_LIMIT).await {
+            Ok(result) => result,
+            Err(error) => {
+                let error = error.to_string();
+                tracing::error!(error, "error getting expired coupons");
+                vec![]
+            }
+        };
+
+        let by_set =
+            expired
+                .into_iter()
+                .fold(BTreeMap::<i64, Vec<Coupon>>::new(), |mut acc, coupon| {
+                    acc.entry(coupon.set_id).or_default().push(coupon);
+                    acc
+                });
+
+        let mut removed: Vec<String> = vec![];
+
+        for (set_id, coupons) in by_set.iter() {
+            match coupon_cache.remove_coupons(*set_id, coupons).await {
+                Ok(coupons) => removed.extend(coupons.into_iter().map(|coupon| coupon.code)),
+                Err(error) => {
+                    let error = error.to_string();
+                    tracing::error!(set_id, error, "error removing expired coupons");
+                }
+            }
+        }
+
+        // Back in the database as unused, where they won't be picked up again since they expired
+        match coupon_database.release_coupons(&removed).await {
+            Ok(rows_affected) => {
+                metrics.purged_coupons.inc_by(rows_affected as f64);
+                tracing::info!(rows_affected, "expired coupons purged from the cache");
+            }
+            Err(error) => {
+                let error = error.to_string();
+                tracing::error!(error, "error releasing expired coupons");
+            }
+        }
+
+        let timeout = *timeout.lock().expect("Could not acquire lock for timeout");
+        tracing::info!(timeout, "purging finished and now waiting");
+        tokio::time::sleep(Duration::from_secs(timeout)).await;
+    }
+}
+
+// The coupons left in the set list go back to the database before the list is dropped, the set
+// ended so the filler doesn't pick them up again. When dropping the list fails, the reconcile job
+// removes the leftovers as orphans.
+async fn purge_set(
+    coupon_database: &CouponRepository,
+    coupon_cache: &mut CouponCache,
+    set_id: i64,
+) -> Result<u64, String> {
+    let codes = coupon_cache
+        .available_coupons(set_id)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    let rows_affected = coupon_database
+        .release_coupons(&codes)
+        .await
+        .map_err(|error| error.to_string())?;
+
+    coupon_cache
+        .purge_sets(&[set_id])
+        .await
+        .map_err(|error| error.to_string())?;
+
+    Ok(rows_affected)
+}
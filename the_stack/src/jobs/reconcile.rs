@@ -0,0 +1,423 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use redis::aio::ConnectionManager;
+use serde::Deserialize;
+use sqlx::Pool;
+use sqlx::Postgres;
+
+use crate::cache::coupon::CouponCache;
+use crate::cache::lock::DistributedLock;
+use crate::database::coupon::CouponRepository;
+use crate::metrics::Metrics;
+use crate::model::coupon::Coupon;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReconcileRepair {
+    // Only report discrepancies
+    Off,
+    // Push stranded coupons back into the set list. A coupon that was popped but lost its used
+    // list entry (e.g. redis lost writes) looks stranded as well, and is handed out a second time.
+    Requeue,
+    // Mark stranded coupons as unused in the database, the filler picks them up again. Without
+    // redis there is no list to push them to, so requeue releases them as well.
+    Release,
+}
+
+// Per set, so that two instances never read and repair the same set at once
+const RECONCILE_LOCK_PREFIX: &str = "thestack::lock::reconcile::";
+const RECONCILE_LOCK_TTL_MILLIS: usize = 60 * 1000;
+
+#[derive(Deserialize, Debug)]
+pub struct ReconcileConfig {
+    #[serde(rename(deserialize = "reconcile_repair"))]
+    pub repair: ReconcileRepair,
+}
+
+// Discrepancies found for a set in the previous run. Lists and rows change between reading the
+// cache and the database (refills, pops that miss the cache, archiving), so a coupon is only
+// repaired once it shows up in two runs in a row.
+struct Discrepancies {
+    stranded: HashSet<String>,
+    orphaned: HashSet<String>,
+}
+
+#[tracing::instrument(skip_all)]
+pub fn setup(
+    cache: ConnectionManager,
+    db: Pool<Postgres>,
+    metrics: Metrics,
+    lock: DistributedLock,
+    timeout: Arc<Mutex<u64>>,
+) -> Result<()> {
+    tracing::info!("Setting up reconcile");
+
+    let config = envy::from_env::<ReconcileConfig>().context("Failed to get env vars")?;
+
+    tokio::task::spawn(async move {
+        reconcile_worker(cache, db, metrics, lock, config, timeout).await;
+    });
+
+    Ok(())
+}
+
+// Without redis, coupons are handed out or reserved in the same statement that takes them, so any
+// taken coupon that is neither was stranded, e.g. by a failed return or a switch from the redis
+// backend
+#[tracing::instrument(skip_all)]
+pub fn setup_postgres(
+    db: Pool<Postgres>,
+    metrics: Metrics,
+    timeout: Arc<Mutex<u64>>,
+) -> Result<()> {
+    tracing::info!("Setting up postgres reconcile");
+
+    let config = envy::from_env::<ReconcileConfig>().context("Failed to get env vars")?;
+
+    tokio::task::spawn(async move {
+        postgres_reconcile_worker(db, metrics, config, timeout).await;
+    });
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn postgres_reconcile_worker(
+    db: Pool<Postgres>,
+    metrics: Metrics,
+    config: ReconcileConfig,
+    timeout: Arc<Mutex<u64>>,
+) {
+    tracing::info!(repair = ?config.repair, "starting postgres reconcile worker loop");
+
+    let coupon_database = CouponRepository::new(db);
+
+    let mut previous = BTreeMap::<i64, HashSet<String>>::new();
+
+    loop {
+        tracing::info!("looking for stranded coupons in the database");
+
+        metrics.job_reconcile.inc();
+
+        match reconcile_database(&coupon_database, &metrics, config.repair, &previous).await {
+            Ok(found) => previous = found,
+            Err(error) => {
+                let error = error.to_string();
+                tracing::error!(error, "error looking for stranded coupons");
+            }
+        }
+
+        let timeout = *timeout.lock().expect("Could not acquire lock for timeout");
+        tracing::info!(timeout, "postgres reconcile finished and now waiting");
+        tokio::time::sleep(Duration::from_secs(timeout)).await;
+    }
+}
+
+// Stranded coupons are released once they show up in two runs in a row, like with redis
+async fn reconcile_database(
+    coupon_database: &CouponRepository,
+    metrics: &Metrics,
+    repair: ReconcileRepair,
+    previous: &BTreeMap<i64, HashSet<String>>,
+) -> Result<BTreeMap<i64, HashSet<String>>> {
+    let set_ids = coupon_database
+        .cached_set_ids()
+        .await
+        .map_err(|error| anyhow!("Error getting sets with taken coupons: {}", error))?;
+
+    let mut found = BTreeMap::new();
+    let mut total_stranded = 0;
+
+    for set_id in set_ids {
+        let stranded = coupon_database
+            .cached_coupons(set_id)
+            .await
+            .map_err(|error| anyhow!("Error reading taken coupons: {}", error))?
+            .into_iter()
+            .collect::<HashSet<String>>();
+
+        if stranded.is_empty() {
+            continue;
+        }
+
+        tracing::warn!(set_id, stranded = stranded.len(), "stranded coupons");
+
+        total_stranded += stranded.len();
+
+        if repair != ReconcileRepair::Off {
+            if let Some(last) = previous.get(&set_id) {
+                let codes = stranded
+                    .intersection(last)
+                    .cloned()
+                    .collect::<Vec<String>>();
+
+                let repaired = coupon_database
+                    .release_coupons(&codes)
+                    .await
+                    .map_err(|error| anyhow!("Error releasing stranded coupons: {}", error))?;
+
+                if repaired > 0 {
+                    tracing::info!(set_id, repaired, "released stranded coupons");
+                }
+
+                metrics.reconciled_coupons.inc_by(repaired as f64);
+            }
+        }
+
+        found.insert(set_id, stranded);
+    }
+
+    metrics.stranded_coupons.set(total_stranded as f64);
+
+    tracing::info!(
+        stranded = total_stranded,
+        "postgres reconciliation finished"
+    );
+
+    Ok(found)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn reconcile_worker(
+    cache: ConnectionManager,
+    db: Pool<Postgres>,
+    metrics: Metrics,
+    lock: DistributedLock,
+    config: ReconcileConfig,
+    timeout: Arc<Mutex<u64>>,
+) {
+    tracing::info!(repair = ?config.repair, "starting reconcile worker loop");
+
+    let coupon_database = CouponRepository::new(db);
+    let mut coupon_cache = CouponCache::new(cache);
+
+    let mut previous = BTreeMap::<i64, Discrepancies>::new();
+
+    loop {
+        tracing::info!("reconciling the cache with the database");
+
+        metrics.job_reconcile.inc();
+
+        match reconcile(
+            &coupon_database,
+            &mut coupon_cache,
+            &metrics,
+            &lock,
+            config.repair,
+            &previous,
+        )
+        .await
+        {
+            Ok(found) => previous = found,
+            Err(error) => {
+                let error = error.to_string();
+                tracing::error!(error, "error reconciling the cache with the database");
+            }
+        }
+
+        let timeout = *timeout.lock().expect("Could not acquire lock for timeout");
+        tracing::info!(timeout, "reconcile finished and now waiting");
+        tokio::time::sleep(Duration::from_secs(timeout)).await;
+    }
+}
+
+async fn reconcile(
+    coupon_database: &CouponRepository,
+    coupon_cache: &mut CouponCache,
+    metrics: &Metrics,
+    lock: &DistributedLock,
+    repair: ReconcileRepair,
+    previous: &BTreeMap<i64, Discrepancies>,
+) -> Result<BTreeMap<i64, Discrepancies>> {
+    // The registry is gone as well when redis lost its data, so sets are taken from both sides
+    let mut set_ids = coupon_database
+        .cached_set_ids()
+        .await
+        .map_err(|error| anyhow!("Error getting cached coupon sets: {}", error))?
+        .into_iter()
+        .collect::<BTreeSet<i64>>();
+    set_ids.extend(
+        coupon_cache
+            .active_set_ids()
+            .await
+            .map_err(|error| anyhow!("Error getting active coupon sets: {}", error))?,
+    );
+
+    let mut found = BTreeMap::new();
+    let mut total_stranded = 0;
+    let mut total_orphaned = 0;
+
+    for set_id in set_ids {
+        let Some(set_lock) = lock
+            .lock_for(
+                &format!("{}{}", RECONCILE_LOCK_PREFIX, set_id),
+                RECONCILE_LOCK_TTL_MILLIS,
+            )
+            .await
+        else {
+            tracing::info!(set_id, "coupon set is already being reconciled");
+            continue;
+        };
+
+        let result = reconcile_set(
+            coupon_database,
+            coupon_cache,
+            metrics,
+            repair,
+            set_id,
+            previous.get(&set_id),
+        )
+        .await;
+
+        lock.unlock(set_lock).await;
+
+        let Some(discrepancies) = result? else {
+            continue;
+        };
+
+        total_stranded += discrepancies.stranded.len();
+        total_orphaned += discrepancies.orphaned.len();
+
+        found.insert(set_id, discrepancies);
+    }
+
+    metrics.stranded_coupons.set(total_stranded as f64);
+    metrics.orphaned_coupons.set(total_orphaned as f64);
+
+    tracing::info!(
+        stranded = total_stranded,
+        orphaned = total_orphaned,
+        "reconciliation finished"
+    );
+
+    Ok(found)
+}
+
+// None when the cache and the database agree
+async fn reconcile_set(
+    coupon_database: &CouponRepository,
+    coupon_cache: &mut CouponCache,
+    metrics: &Metrics,
+    repair: ReconcileRepair,
+    set_id: i64,
+    last: Option<&Discrepancies>,
+) -> Result<Option<Discrepancies>> {
+    // Database first, so that coupons refilled in between show up in the cache, not as orphans
+    let expected = coupon_database
+        .cached_coupons(set_id)
+        .await
+        .map_err(|error| anyhow!("Error reading taken coupons: {}", error))?
+        .into_iter()
+        .collect::<HashSet<String>>();
+    let cached = coupon_cache
+        .cached_coupons(set_id)
+        .await
+        .map_err(|error| anyhow!("Error reading cached coupons: {}", error))?;
+
+    let mut in_cache = cached.taken.into_iter().collect::<HashSet<String>>();
+    in_cache.extend(cached.available.iter().cloned());
+
+    let discrepancies = Discrepancies {
+        stranded: expected.difference(&in_cache).cloned().collect(),
+        orphaned: cached
+            .available
+            .into_iter()
+            .filter(|code| !expected.contains(code))
+            .collect(),
+    };
+
+    if discrepancies.stranded.is_empty() && discrepancies.orphaned.is_empty() {
+        return Ok(None);
+    }
+
+    tracing::warn!(
+        set_id,
+        stranded = discrepancies.stranded.len(),
+        orphaned = discrepancies.orphaned.len(),
+        "cache and database disagree"
+    );
+
+    if repair != ReconcileRepair::Off {
+        if let Some(last) = last {
+            let repaired = repair_set(
+                coupon_database,
+                coupon_cache,
+                repair,
+                set_id,
+                &discrepancies,
+                last,
+            )
+            .await?;
+
+            metrics.reconciled_coupons.inc_by(repaired as f64);
+        }
+    }
+
+    Ok(Some(discrepancies))
+}
+
+async fn repair_set(
+    coupon_database: &CouponRepository,
+    coupon_cache: &mut CouponCache,
+    repair: ReconcileRepair,
+    set_id: i64,
+    current: &Discrepancies,
+    last: &Discrepancies,
+) -> Result<u64> {
+    let to_coupons = |codes: &HashSet<String>, last: &HashSet<String>| {
+        codes
+            .intersection(last)
+            .map(|code| Coupon {
+                code: code.clone(),
+                set_id,
+            })
+            .collect::<Vec<Coupon>>()
+    };
+
+    let stranded = to_coupons(&current.stranded, &last.stranded);
+    let orphaned = to_coupons(&current.orphaned, &last.orphaned);
+
+    let mut repaired = 0;
+
+    // Orphans could be handed out a second time by a pop that misses the cache
+    repaired += coupon_cache
+        .remove_coupons(set_id, &orphaned)
+        .await
+        .map_err(|error| anyhow!("Error removing orphaned coupons: {}", error))?
+        .len() as u64;
+
+    match repair {
+        ReconcileRepair::Requeue if !stranded.is_empty() => {
+            coupon_cache
+                .requeue_coupons(set_id, &stranded)
+                .await
+                .map_err(|error| anyhow!("Error requeueing stranded coupons: {}", error))?;
+            repaired += stranded.len() as u64;
+        }
+        ReconcileRepair::Release => {
+            let codes = stranded
+                .into_iter()
+                .map(|coupon| coupon.code)
+                .collect::<Vec<String>>();
+            repaired += coupon_database
+                .release_coupons(&codes)
+                .await
+                .map_err(|error| anyhow!("Error releasing stranded coupons: {}", error))?;
+        }
+        _ => {}
+    }
+
+    if repaired > 0 {
+        tracing::info!(set_id, repaired, "repaired cache and database drift");
+    }
+
+    Ok(repaired)
+}
@@ -0,0 +1,89 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use chrono::Utc;
+use serde::Deserialize;
+use sqlx::Pool;
+use sqlx::Postgres;
+
+use crate::database::coupon::CouponRepository;
+use crate::metrics::Metrics;
+
+const RETENTION_LIMIT: i64 = 10000;
+
+#[derive(Deserialize, Debug)]
+pub struct RetentionConfig {
+    #[serde(rename(deserialize = "archive_retention_days"))]
+    pub retention_days: i64,
+}
+
+#[tracing::instrument(skip_all)]
+pub fn setup(db: Pool<Postgres>, metrics: Metrics, timeout: Arc<Mutex<u64>>) -> Result<()> {
+    tracing::info!("Setting up retention");
+
+    let config = envy::from_env::<RetentionConfig>().context("Failed to get env vars")?;
+
+    tokio::task::spawn(async move {
+        retention_worker(db, metrics, config, timeout).await;
+    });
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn retention_worker(
+    db: Pool<Postgres>,
+    metrics: Metrics,
+    config: RetentionConfig,
+    timeout: Arc<Mutex<u64>>,
+) {
+    tracing::info!("starting retention worker loop");
+
+    let coupon_database = CouponRepository::new(db);
+
+    loop {
+        tracing::info!("purging archived coupons past retention");
+
+        metrics.job_retention.inc();
+
+        let issued_before = Utc::now() - chrono::Duration::days(config.retention_days);
+
+        // Small batches so that a large backlog doesn't hold locks on the archive for long
+        loop {
+            match coupon_database
+                .purge_archived_coupons(issued_before, RETENTION_LIMIT)
+                .await
+            {
+                Ok(rows_affected) => {
+                    metrics.purged_archived_coupons.inc_by(rows_affected as f64);
+                    tracing::info!(rows_affected, "archived coupons purged");
+
+                    if rows_affected < RETENTION_LIMIT as u64 {
+                        break;
+                    }
+                }
+                Err(error) => {
+                    let error = error.to_string();
+                    tracing::error!(error, "error purging archived coupons");
+                    break;
+                }
+            }
+        }
+
+        // Pop keys are only checked when their key comes back, expired ones would pile up otherwise
+        match coupon_database.purge_idempotency_keys().await {
+            Ok(rows_affected) => tracing::info!(rows_affected, "expired idempotency keys purged"),
+            Err(error) => {
+                let error = error.to_string();
+                tracing::error!(error, "error purging expired idempotency keys");
+            }
+        }
+
+        let timeout = *timeout.lock().expect("Could not acquire lock for timeout");
+        tracing::info!(timeout, "retention finished and now waiting");
+        tokio::time::sleep(Duration::from_secs(timeout)).await;
+    }
+}
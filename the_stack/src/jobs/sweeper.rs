@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use sqlx::Pool;
+use sqlx::Postgres;
+use uuid::Uuid;
+
+use crate::database::coupon::CouponRepository;
+use crate::metrics::Metrics;
+use crate::model::coupon::Coupon;
+use crate::model::coupon::CouponReservation;
+use crate::store::CouponStore;
+
+const SWEEP_LIMIT: i64 = 10000;
+
+#[tracing::instrument(skip_all)]
+pub fn setup(
+    store: Arc<dyn CouponStore>,
+    db: Pool<Postgres>,
+    metrics: Metrics,
+    timeout: Arc<Mutex<u64>>,
+) -> Result<()> {
+    tracing::info!("Setting up sweeper");
+
+    tokio::task::spawn(async move {
+        sweeper_worker(store, db, metrics, timeout).await;
+    });
+
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn sweeper_worker(
+    store: Arc<dyn CouponStore>,
+    db: Pool<Postgres>,
+    metrics: Metrics,
+    timeout: Arc<Mutex<u64>>,
+) {
+    tracing::info!("starting sweeper worker loop");
+
+    let coupon_database = CouponRepository::new(db);
+
+    loop {
+        tracing::info!("sweeping expired reservations");
+
+        // Coupons go back to their sets first and their reservations are cleared afterwards, a
+        // failure on either side leaves the reservation in place for the next sweep
+        loop {
+            let expired = match coupon_database.expired_reservations(SWEEP_LIMIT).await {
+                Ok(result) => result,
+                Err(error) => {
+                    let error = error.to_string();
+                    tracing::error!(error, "error expiring reservations");
+                    break;
+                }
+            };
+
+            if expired.is_empty() {
+                break;
+            }
+
+            // Only inc when we are actually sweeping something
+            metrics.job_sweeper.inc();
+
+            let total = expired.len();
+
+            tracing::info!("returning {} expired reservations to their sets", total);
+
+            let by_set = expired.into_iter().fold(
+                BTreeMap::<i64, Vec<CouponReservation>>::new(),
+                |mut acc, reservation| {
+                    acc.entry(reservation.set_id).or_default().push(reservation);
+                    acc
+                },
+            );
+
+            let mut failed = false;
+
+            for (set_id, reservations) in by_set.iter() {
+                let coupons = reservations
+                    .iter()
+                    .map(|reservation| Coupon {
+                        code: reservation.code.clone(),
+                        set_id: *set_id,
+                    })
+                    .collect::<Vec<Coupon>>();
+
+                if let Err(error) = store.return_coupons(*set_id, &coupons).await {
+                    let error = error.to_string();
+                    tracing::error!(set_id, error, "error returning expired reservations");
+
+                    failed = true;
+                    continue;
+                }
+
+                let reservation_ids = reservations
+                    .iter()
+                    .map(|reservation| reservation.reservation_id)
+                    .collect::<Vec<Uuid>>();
+
+                match coupon_database.clear_reservations(&reservation_ids).await {
+                    Ok(_) => metrics.reservations_expired.inc_by(coupons.len() as f64),
+                    Err(error) => {
+                        let error = error.to_string();
+                        tracing::error!(set_id, error, "error clearing expired reservations");
+
+                        failed = true;
+                    }
+                }
+            }
+
+            // The same reservations would be read again right away
+            if failed || (total as i64) < SWEEP_LIMIT {
+                break;
+            }
+        }
+
+        let timeout = *timeout.lock().expect("Could not acquire lock for timeout");
+        tracing::info!(timeout, "sweeping finished and now waiting");
+        tokio::time::sleep(Duration::from_secs(timeout)).await;
+    }
+}
@@ -2,19 +2,24 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
 
+use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
-use redis::aio::MultiplexedConnection;
-use redis::AsyncCommands;
+use redis::aio::ConnectionManager;
 use serde::Deserialize;
 use sqlx::Pool;
 use sqlx::Postgres;
-use uuid::Uuid;
 
 use crate::cache::coupon::CouponCache;
+use crate::cache::lock::DistributedLock;
 use crate::database::coupon::CouponRepository;
 use crate::metrics::Metrics;
-use crate::model::coupon::CouponSet;
+
+const CLEANUP_BATCH_SIZE: i64 = 10000;
+const CLEANUP_LOCK: &str = "thestack::lock::cleanup";
+const CLEANUP_LOCK_TTL_MILLIS: usize = 5 * 60 * 1000;
+const BACKOFF_BASE_SECONDS: u64 = 1;
+const BACKOFF_MAX_SECONDS: u64 = 60;
 
 #[derive(Deserialize, Debug)]
 pub struct WorkerConfig {
@@ -22,100 +27,146 @@ pub struct WorkerConfig {
     pub timeout_seconds: u64,
 }
 
+// Shared by all jobs, and changed through the worker API
+pub fn timeout() -> Result<Arc<Mutex<u64>>> {
+    let config = envy::from_env::<WorkerConfig>().context("Failed to get env vars")?;
+
+    Ok(Arc::new(Mutex::new(config.timeout_seconds)))
+}
+
 #[tracing::instrument(skip_all)]
 pub fn setup(
-    cache: MultiplexedConnection,
+    cache: ConnectionManager,
     db: Pool<Postgres>,
     metrics: Metrics,
-) -> Result<Arc<Mutex<u64>>> {
+    lock: DistributedLock,
+    timeout: Arc<Mutex<u64>>,
+) -> Result<()> {
     tracing::info!("Setting up worker");
 
-    let config = envy::from_env::<WorkerConfig>().context("Failed to get env vars")?;
-
-    let timeout = Arc::new(Mutex::new(config.timeout_seconds));
-
-    {
-        let timeout = timeout.clone();
-        tokio::task::spawn(async move {
-            cleanup_worker(cache, db, metrics, timeout).await;
-        });
-    }
+    tokio::task::spawn(async move {
+        cleanup_worker(cache, db, metrics, lock, timeout).await;
+    });
 
-    Ok(timeout)
+    Ok(())
 }
 
 #[tracing::instrument(skip_all)]
 pub async fn cleanup_worker(
-    mut cache: MultiplexedConnection,
+    cache: ConnectionManager,
     db: Pool<Postgres>,
     metrics: Metrics,
+    lock: DistributedLock,
     timeout: Arc<Mutex<u64>>,
 ) {
     tracing::info!("starting cleanup worker loop");
 
+    let coupon_database = CouponRepository::new(db);
+    let mut coupon_cache = CouponCache::new(cache);
+    let mut failures: u32 = 0;
+
     loop {
         tracing::info!("cleaning up used coupons");
 
-        let keys = match cache
-            .keys::<_, Vec<String>>(CouponSet::used_key_prefix())
-            .await
-        {
-            Ok(result) => result,
-            Err(err) => {
-                let error = anyhow::Error::from(err).to_string();
-                tracing::error!(error, "Error getting all keys for cleanup");
-                continue;
+        // Only one instance cleans up at a time, so that in-flight lists have a single owner
+        let result = match lock.lock_for(CLEANUP_LOCK, CLEANUP_LOCK_TTL_MILLIS).await {
+            Some(cleanup_lock) => {
+                let result = cleanup(&mut coupon_cache, &coupon_database, &metrics).await;
+                lock.unlock(cleanup_lock).await;
+                result
+            }
+            None => {
+                tracing::info!("another instance is cleaning up");
+                Ok(())
             }
         };
 
-        if keys.is_empty() {
-            let timeout = *timeout.lock().expect("Could not acquire lock for timeout");
-            tracing::info!(timeout, "nothing to cleanup and now waiting");
-            tokio::time::sleep(Duration::from_secs(timeout)).await;
-            continue;
-        }
+        let wait = match result {
+            Ok(()) => {
+                failures = 0;
+                *timeout.lock().expect("Could not acquire lock for timeout")
+            }
+            Err(error) => {
+                failures += 1;
 
-        // Only inc when we are actually cleaning up something
-        metrics.job_cleanup.inc();
+                let error = error.to_string();
+                tracing::error!(error, failures, "error when cleaning up used coupons");
 
-        tracing::info!("cleaning up {} coupon sets", &keys.len());
+                backoff_seconds(failures)
+            }
+        };
 
-        let mut coupon_cache = CouponCache::new(cache.clone());
-        let mut coupons_to_delete = vec![];
+        tracing::info!(wait, "cleaning up finished and now waiting");
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+    }
+}
 
-        for key in keys.iter() {
-            let Ok(mut coupons) = coupon_cache.pop_coupon_list(key).await else {
-                continue;
-            };
+// Used coupons are moved to an in-flight list before they are archived, and only dropped from it
+// after the database commit. Whatever is still in flight, after a crash or a failed archive, is
+// archived again before a new batch is taken, which also covers the first run after a restart.
+async fn cleanup(
+    coupon_cache: &mut CouponCache,
+    coupon_database: &CouponRepository,
+    metrics: &Metrics,
+) -> Result<()> {
+    let set_ids = coupon_cache
+        .used_set_ids()
+        .await
+        .map_err(|error| anyhow!("Error getting coupon sets for cleanup: {}", error))?;
+
+    if set_ids.is_empty() {
+        tracing::info!("nothing to cleanup");
+        return Ok(());
+    }
 
-            coupons_to_delete.append(&mut coupons);
-        }
+    // Only inc when we are actually cleaning up something
+    metrics.job_cleanup.inc();
 
-        // TODO this number does not match rows_affected
-        tracing::info!(
-            "cleaning up {} coupons from the database",
-            coupons_to_delete.len()
-        );
+    tracing::info!("cleaning up {} coupon sets", set_ids.len());
 
-        let coupon_database = CouponRepository::new(db.clone());
+    for set_id in set_ids.into_iter() {
+        let mut coupons = coupon_cache
+            .in_flight_coupons(set_id)
+            .await
+            .map_err(|error| anyhow!("Error reading in-flight coupons: {}", error))?;
+
+        if coupons.is_empty() {
+            coupons = coupon_cache
+                .claim_used_coupons(set_id, CLEANUP_BATCH_SIZE)
+                .await
+                .map_err(|error| anyhow!("Error claiming used coupons: {}", error))?;
+        } else {
+            tracing::warn!(set_id, "recovering {} in-flight coupons", coupons.len());
+        }
 
-        let coupons_to_delete = coupons_to_delete
-            .iter()
-            .map(|value| Uuid::try_parse(value).expect("Could not parse UUID"))
-            .collect::<Vec<Uuid>>();
+        if coupons.is_empty() {
+            continue;
+        }
 
-        let result = coupon_database.delete_coupons(&coupons_to_delete).await;
+        let issued = coupon_cache
+            .issued_coupons(set_id, &coupons)
+            .await
+            .map_err(|error| anyhow!("Error reading who coupons were issued to: {}", error))?;
 
-        match result {
-            Ok(rows_affected) => tracing::info!(rows_affected, "coupons deleted from the database"),
-            Err(error) => {
-                let error = error.to_string();
-                tracing::error!(error, "error when deleting coupons from the database");
-            }
-        }
+        let rows_affected = coupon_database
+            .archive_coupons(&coupons, &issued)
+            .await
+            .map_err(|error| anyhow!("Error archiving coupons: {}", error))?;
 
-        let timeout = *timeout.lock().expect("Could not acquire lock for timeout");
-        tracing::info!(timeout, "cleaning up finished and now waiting");
-        tokio::time::sleep(Duration::from_secs(timeout)).await;
+        coupon_cache
+            .ack_in_flight(set_id, &coupons)
+            .await
+            .map_err(|error| anyhow!("Error acknowledging in-flight coupons: {}", error))?;
+
+        metrics.archived_coupons.inc_by(rows_affected as f64);
+        tracing::info!(set_id, rows_affected, "coupons archived");
     }
+
+    Ok(())
+}
+
+fn backoff_seconds(failures: u32) -> u64 {
+    BACKOFF_BASE_SECONDS
+        .saturating_mul(2u64.saturating_pow(failures.saturating_sub(1)))
+        .min(BACKOFF_MAX_SECONDS)
 }
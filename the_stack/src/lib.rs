@@ -8,4 +8,5 @@ pub mod jobs;
 pub mod metrics;
 pub mod model;
 pub mod service;
+pub mod store;
 pub mod tracing;
@@ -1,33 +1,99 @@
+use std::sync::Arc;
+
+use the_stack::store::CouponStore;
+use the_stack::store::CouponStoreBackend;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenvy::dotenv().ok();
 
     let env = the_stack::tracing::setup();
     let metrics = the_stack::metrics::setup(&env)?;
-    let batch_config = the_stack::service::BatchInsertConfig::new()?;
+    let coupon_config = the_stack::service::CouponServiceConfig::new()?;
+    let claim_config = the_stack::service::ClaimConfig::new()?;
+    let generate_config = the_stack::service::GenerateConfig::new()?;
     let db = the_stack::database::setup(&env).await?;
     let jwt_service = the_stack::auth::jwt::setup()?;
-    let (cache, lock) = the_stack::cache::setup(&env).await?;
-    let timeout = the_stack::jobs::worker::setup(cache.clone(), db.clone(), metrics.clone())?;
+    let store_config = the_stack::store::CouponStoreConfig::new()?;
+    let timeout = the_stack::jobs::worker::timeout()?;
+    let coupon_repo = the_stack::database::coupon::CouponRepository::new(db.clone());
+    let store: Arc<dyn CouponStore> = match store_config.backend {
+        CouponStoreBackend::Redis => {
+            let (cache, lock) = the_stack::cache::setup(&env).await?;
+            the_stack::jobs::worker::setup(
+                cache.clone(),
+                db.clone(),
+                metrics.clone(),
+                lock.clone(),
+                timeout.clone(),
+            )?;
+            the_stack::jobs::filler::setup(
+                cache.clone(),
+                db.clone(),
+                metrics.clone(),
+                lock.clone(),
+                coupon_config.batch.clone(),
+                timeout.clone(),
+            )?;
+            the_stack::jobs::purger::setup(
+                cache.clone(),
+                db.clone(),
+                metrics.clone(),
+                timeout.clone(),
+            )?;
+            the_stack::jobs::reconcile::setup(
+                cache.clone(),
+                db.clone(),
+                metrics.clone(),
+                lock.clone(),
+                timeout.clone(),
+            )?;
+
+            Arc::new(the_stack::store::redis::RedisCouponStore::new(
+                coupon_repo,
+                the_stack::cache::coupon::CouponCache::new(cache),
+                metrics.clone(),
+                lock,
+                coupon_config.clone(),
+            ))
+        }
+        CouponStoreBackend::Postgres => {
+            tracing::info!("Running without redis, coupons are handed out from postgres");
+            the_stack::jobs::reconcile::setup_postgres(
+                db.clone(),
+                metrics.clone(),
+                timeout.clone(),
+            )?;
+
+            Arc::new(the_stack::store::postgres::PostgresCouponStore::new(
+                coupon_repo,
+            ))
+        }
+    };
+    the_stack::jobs::sweeper::setup(store.clone(), db.clone(), metrics.clone(), timeout.clone())?;
+    the_stack::jobs::retention::setup(db.clone(), metrics.clone(), timeout.clone())?;
     let user_login = the_stack::service::userlogin::UserLoginService::new(
         the_stack::database::userlogin::UserLoginRepository::new(db.clone()),
     )?;
     let user_auth =
         the_stack::auth::userlogin::UserAuthMiddleware::new(jwt_service.clone(), user_login);
-    let kc_auth = the_stack::auth::keycloak::KeycloakAuthMiddleware::new()?;
+    let kc_auth = the_stack::auth::keycloak::KeycloakAuthMiddleware::new(metrics.clone())?;
+    let role_config = the_stack::auth::role::RoleConfig::new()?;
 
     the_stack::api::setup(
         &env,
         the_stack::api::AppState {
             db,
-            cache,
+            store,
             metrics,
             timeout,
             jwt_service,
-            lock,
-            batch_config,
+            coupon_config,
+            claim_config,
+            generate_config,
             user_auth,
             kc_auth,
+            role_config,
         },
     )
     .await?;
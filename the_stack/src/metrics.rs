@@ -1,4 +1,5 @@
 use prometheus::Counter;
+use prometheus::Gauge;
 use prometheus::Histogram;
 use prometheus::HistogramOpts;
 use prometheus::Opts;
@@ -12,11 +13,38 @@ pub struct Metrics {
 
     pub cache_hit: Counter,
     pub cache_miss: Counter,
+    pub cache_breaker_state: Gauge,
+    pub cache_bypassed: Counter,
+
+    pub idempotent_replays: Counter,
+    pub coupons_redeemed: Counter,
 
     pub req_elapsed: Histogram,
 
+    pub jwks_fetches: Counter,
+    pub jwks_fetch_failures: Counter,
+    pub jwks_unknown_kid: Counter,
+
     pub job_cleanup: Counter,
     pub job_upload: Counter,
+    pub job_generate: Counter,
+    pub job_filler: Counter,
+    pub job_sweeper: Counter,
+    pub job_purger: Counter,
+    pub job_retention: Counter,
+    pub job_reconcile: Counter,
+
+    pub filler_refills: Counter,
+    pub filler_coupons: Counter,
+
+    pub reservations_expired: Counter,
+    pub purged_coupons: Counter,
+    pub archived_coupons: Counter,
+    pub purged_archived_coupons: Counter,
+
+    pub stranded_coupons: Gauge,
+    pub orphaned_coupons: Gauge,
+    pub reconciled_coupons: Counter,
 
     pub batch_inserts: Counter,
 }
@@ -40,6 +68,27 @@ pub fn setup(env: &str) -> anyhow::Result<Metrics> {
     let cache_miss = Counter::with_opts(Opts::new("cache_miss", "Cache miss count"))?;
     r.register(Box::new(cache_hit.clone()))?;
     r.register(Box::new(cache_miss.clone()))?;
+    let cache_breaker_state = Gauge::with_opts(Opts::new(
+        "cache_breaker_state",
+        "State of the cache circuit breaker: 0 closed, 1 half open, 2 open",
+    ))?;
+    r.register(Box::new(cache_breaker_state.clone()))?;
+    let cache_bypassed = Counter::with_opts(Opts::new(
+        "cache_bypassed",
+        "How many coupons were handed out straight from the database while the cache was down",
+    ))?;
+    r.register(Box::new(cache_bypassed.clone()))?;
+
+    let idempotent_replays = Counter::with_opts(Opts::new(
+        "idempotent_replays",
+        "How many coupon pops and redemptions were replayed from an idempotency key",
+    ))?;
+    r.register(Box::new(idempotent_replays.clone()))?;
+    let coupons_redeemed = Counter::with_opts(Opts::new(
+        "coupons_redeemed",
+        "How many coupons were redeemed",
+    ))?;
+    r.register(Box::new(coupons_redeemed.clone()))?;
 
     let req_elapsed =
         Histogram::with_opts(HistogramOpts::new("req_elapsed", "Request elapsed time"))?;
@@ -53,6 +102,92 @@ pub fn setup(env: &str) -> anyhow::Result<Metrics> {
     let job_upload =
         Counter::with_opts(Opts::new("job_upload", "How many times the upload job ran"))?;
     r.register(Box::new(job_upload.clone()))?;
+    let job_generate = Counter::with_opts(Opts::new(
+        "job_generate",
+        "How many times the generate job ran",
+    ))?;
+    r.register(Box::new(job_generate.clone()))?;
+    let job_filler =
+        Counter::with_opts(Opts::new("job_filler", "How many times the filler job ran"))?;
+    r.register(Box::new(job_filler.clone()))?;
+    let job_sweeper = Counter::with_opts(Opts::new(
+        "job_sweeper",
+        "How many times the sweeper job ran",
+    ))?;
+    r.register(Box::new(job_sweeper.clone()))?;
+    let job_purger =
+        Counter::with_opts(Opts::new("job_purger", "How many times the purger job ran"))?;
+    r.register(Box::new(job_purger.clone()))?;
+    let job_retention = Counter::with_opts(Opts::new(
+        "job_retention",
+        "How many times the retention job ran",
+    ))?;
+    r.register(Box::new(job_retention.clone()))?;
+    let job_reconcile = Counter::with_opts(Opts::new(
+        "job_reconcile",
+        "How many times the reconcile job ran",
+    ))?;
+    r.register(Box::new(job_reconcile.clone()))?;
+    let filler_refills = Counter::with_opts(Opts::new(
+        "filler_refills",
+        "How many coupon sets were refilled by the filler job",
+    ))?;
+    r.register(Box::new(filler_refills.clone()))?;
+    let filler_coupons = Counter::with_opts(Opts::new(
+        "filler_coupons",
+        "How many coupons were moved to the cache by the filler job",
+    ))?;
+    r.register(Box::new(filler_coupons.clone()))?;
+    let reservations_expired = Counter::with_opts(Opts::new(
+        "reservations_expired",
+        "How many expired reservations were returned to their coupon sets",
+    ))?;
+    r.register(Box::new(reservations_expired.clone()))?;
+    let purged_coupons = Counter::with_opts(Opts::new(
+        "purged_coupons",
+        "How many expired coupons were purged from the cache",
+    ))?;
+    r.register(Box::new(purged_coupons.clone()))?;
+    let archived_coupons = Counter::with_opts(Opts::new(
+        "archived_coupons",
+        "How many handed out coupons were moved to the archive",
+    ))?;
+    r.register(Box::new(archived_coupons.clone()))?;
+    let purged_archived_coupons = Counter::with_opts(Opts::new(
+        "purged_archived_coupons",
+        "How many archived coupons were deleted after the retention period",
+    ))?;
+    r.register(Box::new(purged_archived_coupons.clone()))?;
+    let stranded_coupons = Gauge::with_opts(Opts::new(
+        "stranded_coupons",
+        "Coupons taken from the database but missing from the cache, as of the last reconciliation",
+    ))?;
+    r.register(Box::new(stranded_coupons.clone()))?;
+    let orphaned_coupons = Gauge::with_opts(Opts::new(
+        "orphaned_coupons",
+        "Coupons in the cache that the database doesn't have as taken, as of the last reconciliation",
+    ))?;
+    r.register(Box::new(orphaned_coupons.clone()))?;
+    let reconciled_coupons = Counter::with_opts(Opts::new(
+        "reconciled_coupons",
+        "How many stranded or orphaned coupons were repaired by the reconcile job",
+    ))?;
+    r.register(Box::new(reconciled_coupons.clone()))?;
+    let jwks_fetches = Counter::with_opts(Opts::new(
+        "jwks_fetches",
+        "How many times the Keycloak JWKS was fetched",
+    ))?;
+    r.register(Box::new(jwks_fetches.clone()))?;
+    let jwks_fetch_failures = Counter::with_opts(Opts::new(
+        "jwks_fetch_failures",
+        "How many Keycloak JWKS fetches failed",
+    ))?;
+    r.register(Box::new(jwks_fetch_failures.clone()))?;
+    let jwks_unknown_kid = Counter::with_opts(Opts::new(
+        "jwks_unknown_kid",
+        "How many tokens were signed with a key id missing from the cached JWKS",
+    ))?;
+    r.register(Box::new(jwks_unknown_kid.clone()))?;
     let batch_inserts = Counter::with_opts(Opts::new(
         "batch_inserts",
         "How many times the batch_inserts were performed",
@@ -68,9 +203,31 @@ pub fn setup(env: &str) -> anyhow::Result<Metrics> {
         api_5xx,
         cache_hit,
         cache_miss,
+        cache_breaker_state,
+        cache_bypassed,
+        idempotent_replays,
+        coupons_redeemed,
         req_elapsed,
+        jwks_fetches,
+        jwks_fetch_failures,
+        jwks_unknown_kid,
         job_cleanup,
         job_upload,
+        job_generate,
+        job_filler,
+        job_sweeper,
+        job_purger,
+        job_retention,
+        job_reconcile,
+        filler_refills,
+        filler_coupons,
+        reservations_expired,
+        purged_coupons,
+        archived_coupons,
+        purged_archived_coupons,
+        stranded_coupons,
+        orphaned_coupons,
+        reconciled_coupons,
         batch_inserts,
     })
 }
@@ -5,11 +5,76 @@ use serde::Serialize;
 use sqlx::FromRow;
 use uuid::Uuid;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum CouponSetStatus {
+    Active,
+    Paused,
+    Archived,
+}
+
+// Longest code any format can produce, so that a code always fits in the cache and in a CSV line
+pub const MAX_CODE_LENGTH: usize = 255;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CouponCodeFormat {
+    #[default]
+    Uuid,
+    Alphanumeric {
+        length: usize,
+        #[serde(default = "CouponCodeFormat::default_alphabet")]
+        alphabet: String,
+    },
+    Text {
+        max_length: usize,
+    },
+}
+
+impl CouponCodeFormat {
+    fn default_alphabet() -> String {
+        "ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789".to_string()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Self::Uuid => true,
+            Self::Alphanumeric { length, alphabet } => {
+                (1..=MAX_CODE_LENGTH).contains(length) && !alphabet.is_empty()
+            }
+            Self::Text { max_length } => (1..=MAX_CODE_LENGTH).contains(max_length),
+        }
+    }
+
+    // Returns the code the way it is stored, or None when it doesn't match the format. UUIDs are
+    // stored hyphenated and lowercase so that lookups don't depend on how they were uploaded.
+    pub fn normalize(&self, code: &str) -> Option<String> {
+        let code = code.trim();
+
+        match self {
+            Self::Uuid => Uuid::try_parse(code).ok().map(|id| id.to_string()),
+            Self::Alphanumeric { length, alphabet } => (code.chars().count() == *length
+                && code.chars().all(|c| alphabet.contains(c)))
+            .then(|| code.to_string()),
+            Self::Text { max_length } => {
+                (!code.is_empty() && code.chars().count() <= *max_length).then(|| code.to_string())
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct CouponSet {
     pub id: i64,
     pub name: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    pub starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub ends_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub status: CouponSetStatus,
+    #[sqlx(json)]
+    pub code_format: CouponCodeFormat,
+    pub sticky: bool,
 }
 
 impl CouponSet {
@@ -29,18 +94,143 @@ impl CouponSet {
         "thestack::used::*"
     }
 
-    pub fn extract_set_id(key: &str) -> i64 {
-        // TODO how to better treat -1 ids
-        let id_str = key.split("::").nth(2).unwrap_or("-1");
+    pub fn in_flight_key(id: i64) -> String {
+        format!("thestack::inflight::{}", id)
+    }
+
+    pub fn in_flight_key_prefix() -> &'static str {
+        "thestack::inflight::*"
+    }
+
+    pub fn reserved_key(id: i64) -> String {
+        format!("thestack::reserved::{}", id)
+    }
+
+    // Hash of coupon to who it was handed out to and when (see `CouponIssue`), until the coupon is
+    // archived
+    pub fn issued_key(id: i64) -> String {
+        format!("thestack::issued::{}", id)
+    }
+
+    // Hash of subject to coupon for sticky sets
+    pub fn assigned_key(id: i64) -> String {
+        format!("thestack::assigned::{}", id)
+    }
+
+    // Keys are scoped to the subject, another subject reusing a key makes a new request
+    pub fn idempotency_key(id: i64, subject: &str, key: &str) -> String {
+        format!("thestack::idempotency::{}::{}::{}", id, subject, key)
+    }
+
+    pub fn refill_key(id: i64) -> String {
+        format!("thestack::refill::{}", id)
+    }
+
+    // Set ids of every set that may have coupons in the cache
+    pub fn registry_key() -> &'static str {
+        "thestack::sets"
+    }
 
-        str::parse(id_str).unwrap_or(-1)
+    // None when the key doesn't follow the `thestack::<list>::<id>` layout
+    pub fn extract_set_id(key: &str) -> Option<i64> {
+        key.split("::").nth(2)?.parse().ok()
+    }
+}
+
+pub enum IdempotencyClaim {
+    New,
+    Pending,
+    Replay(String),
+}
+
+// A handed out coupon in the issued hash, stored as `<microseconds since epoch>:<subject>`.
+// Entries from before the time was recorded only hold the subject.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CouponIssue {
+    pub issued_to: String,
+    pub issued_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl CouponIssue {
+    pub fn new(issued_to: &str) -> Self {
+        Self {
+            issued_to: issued_to.to_string(),
+            issued_at: Some(chrono::Utc::now()),
+        }
+    }
+
+    pub fn to_value(&self) -> String {
+        match self.issued_at {
+            Some(issued_at) => format!("{}:{}", issued_at.timestamp_micros(), self.issued_to),
+            None => self.issued_to.clone(),
+        }
+    }
+
+    pub fn from_value(value: &str) -> Self {
+        let issued = value.split_once(':').and_then(|(micros, issued_to)| {
+            let micros = micros.parse().ok()?;
+            let issued_at = chrono::DateTime::from_timestamp_micros(micros)?;
+            Some((issued_to, issued_at))
+        });
+
+        match issued {
+            Some((issued_to, issued_at)) => Self {
+                issued_to: issued_to.to_string(),
+                issued_at: Some(issued_at),
+            },
+            None => Self {
+                issued_to: value.to_string(),
+                issued_at: None,
+            },
+        }
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow, FromRedisValue, ToRedisArgs)]
 pub struct Coupon {
-    pub id: Uuid,
+    pub code: String,
+    pub set_id: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CouponStatus {
+    Available,
+    InCache,
+    Reserved,
+    HandedOut,
+    Redeemed,
+    Expired,
+}
+
+// The full row of a coupon, for lookups
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct CouponRecord {
+    pub code: String,
+    pub set_id: i64,
+    pub used: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub reservation_id: Option<Uuid>,
+    pub reserved_until: Option<chrono::DateTime<chrono::Utc>>,
+    pub issued_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub redeemed_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub redemption_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ExistingCoupon {
+    pub code: String,
+    pub set_id: i64,
+    pub upload_job_id: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct CouponReservation {
+    pub reservation_id: Uuid,
+    pub code: String,
     pub set_id: i64,
+    pub reserved_until: chrono::DateTime<chrono::Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -48,7 +238,10 @@ pub struct CouponSetDatabaseStatus {
     pub id: i64,
     pub name: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    pub status: CouponSetStatus,
     pub total_coupons: i64,
+    pub total_issued: i64,
+    pub total_redeemed: i64,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
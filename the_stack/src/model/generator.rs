@@ -0,0 +1,114 @@
+use rand::Rng;
+use serde::Deserialize;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::model::coupon::CouponCodeFormat;
+use crate::model::coupon::MAX_CODE_LENGTH;
+
+// Crockford's base32, without the letters that are easily mistaken for digits
+pub const BASE32_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CodeGenerator {
+    UuidV4,
+    UuidV7,
+    // `length` random characters followed by a check character, wrapped in the prefix and suffix
+    Base32 {
+        length: usize,
+        #[serde(default)]
+        prefix: String,
+        #[serde(default)]
+        suffix: String,
+    },
+}
+
+impl CodeGenerator {
+    fn code_length(&self) -> usize {
+        match self {
+            Self::UuidV4 | Self::UuidV7 => 36,
+            Self::Base32 {
+                length,
+                prefix,
+                suffix,
+            } => prefix.chars().count() + length + 1 + suffix.chars().count(),
+        }
+    }
+
+    // How many distinct codes the generator can produce, `None` when there are too many to run out
+    pub fn keyspace(&self) -> Option<u128> {
+        match self {
+            Self::UuidV4 | Self::UuidV7 => None,
+            Self::Base32 { length, .. } => u32::try_from(*length)
+                .ok()
+                .and_then(|length| (BASE32_ALPHABET.len() as u128).checked_pow(length)),
+        }
+    }
+
+    // Whether every code this generator produces is accepted by the set's format
+    pub fn fits(&self, format: &CouponCodeFormat) -> bool {
+        if self.code_length() > MAX_CODE_LENGTH {
+            return false;
+        }
+
+        match (self, format) {
+            (Self::Base32 { length: 0, .. }, _) => false,
+            (Self::UuidV4 | Self::UuidV7, CouponCodeFormat::Uuid) => true,
+            (Self::Base32 { .. }, CouponCodeFormat::Uuid) => false,
+            (Self::UuidV4 | Self::UuidV7, CouponCodeFormat::Alphanumeric { .. }) => false,
+            (
+                Self::Base32 { prefix, suffix, .. },
+                CouponCodeFormat::Alphanumeric { length, alphabet },
+            ) => {
+                self.code_length() == *length
+                    && BASE32_ALPHABET
+                        .iter()
+                        .map(|c| *c as char)
+                        .chain(prefix.chars())
+                        .chain(suffix.chars())
+                        .all(|c| alphabet.contains(c))
+            }
+            (_, CouponCodeFormat::Text { max_length }) => self.code_length() <= *max_length,
+        }
+    }
+
+    pub fn generate<R: Rng>(&self, rng: &mut R) -> String {
+        match self {
+            Self::UuidV4 => Uuid::new_v4().to_string(),
+            Self::UuidV7 => Uuid::now_v7().to_string(),
+            Self::Base32 {
+                length,
+                prefix,
+                suffix,
+            } => {
+                let body = (0..*length)
+                    .map(|_| rng.gen_range(0..BASE32_ALPHABET.len()))
+                    .collect::<Vec<usize>>();
+
+                let check = luhn_check_digit(&body);
+
+                let mut code = String::with_capacity(self.code_length());
+                code.push_str(prefix);
+                code.extend(body.iter().map(|i| BASE32_ALPHABET[*i] as char));
+                code.push(BASE32_ALPHABET[check] as char);
+                code.push_str(suffix);
+                code
+            }
+        }
+    }
+}
+
+// Luhn mod N over the indices of the characters in the alphabet, catches any single mistyped
+// character and most swaps of adjacent characters
+fn luhn_check_digit(body: &[usize]) -> usize {
+    let n = BASE32_ALPHABET.len();
+
+    let sum = body.iter().rev().enumerate().fold(0, |sum, (i, value)| {
+        let factor = if i % 2 == 0 { 2 } else { 1 };
+        let addend = factor * value;
+        sum + addend / n + addend % n
+    });
+
+    (n - sum % n) % n
+}
@@ -1,2 +1,4 @@
 pub mod coupon;
+pub mod generator;
+pub mod upload;
 pub mod userlogin;
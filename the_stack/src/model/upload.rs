@@ -0,0 +1,59 @@
+use serde::Deserialize;
+use serde::Serialize;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum UploadJobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum UploadJobKind {
+    Upload,
+    Generate,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct UploadJob {
+    pub id: Uuid,
+    pub set_id: i64,
+    pub kind: UploadJobKind,
+    pub state: UploadJobState,
+    pub parsed: i64,
+    pub rejected: i64,
+    pub inserted: i64,
+    pub error: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UploadJobCounts {
+    pub parsed: i64,
+    pub rejected: i64,
+    pub inserted: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum UploadRejectionReason {
+    Invalid,
+    Duplicate,
+    Existing,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct UploadRejection {
+    pub code: String,
+    pub reason: UploadRejectionReason,
+    pub existing_set_id: Option<i64>,
+}
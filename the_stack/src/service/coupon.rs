@@ -1,157 +1,817 @@
-use tokio_retry::strategy::jitter;
-use tokio_retry::strategy::ExponentialBackoff;
-use tokio_retry::Retry;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::Utc;
+use futures::Stream;
+use tokio::sync::mpsc;
 use uuid::Uuid;
 
+use crate::api::dto::CouponLookupResponseDto;
 use crate::api::dto::CouponStatusResponseDto;
 use crate::api::dto::CreateCouponSetDto;
-use crate::cache::coupon::CouponCache;
-use crate::cache::lock::DistributedLock;
+use crate::api::dto::ExistingCouponDto;
+use crate::api::dto::GenerateCouponsDto;
+use crate::api::dto::UploadReportResponseDto;
+use crate::auth::Principal;
 use crate::database::coupon::CouponRepository;
+use crate::database::upload::UploadJobRepository;
+use crate::error::database::DatabaseError;
 use crate::error::service::ServiceError;
 use crate::error::service::ServiceResult;
 use crate::metrics::Metrics;
 use crate::model::coupon::Coupon;
+use crate::model::coupon::CouponRecord;
+use crate::model::coupon::CouponReservation;
 use crate::model::coupon::CouponSet;
-use crate::service::BatchInsertConfig;
-
+use crate::model::coupon::CouponSetStatus;
+use crate::model::coupon::CouponStatus;
+use crate::model::coupon::ExistingCoupon;
+use crate::model::coupon::IdempotencyClaim;
+use crate::model::upload::UploadJob;
+use crate::model::upload::UploadJobCounts;
+use crate::model::upload::UploadJobKind;
+use crate::model::upload::UploadJobState;
+use crate::model::upload::UploadRejection;
+use crate::model::upload::UploadRejectionReason;
+use crate::service::metadata::CouponSetMetadata;
+use crate::service::CouponServiceConfig;
+use crate::store::CouponStore;
+
+const UPLOAD_CHANNEL_SIZE: usize = 2;
+const GENERATE_MAX_ATTEMPTS: usize = 3;
+// Random draws per code in a chunk before the generator is considered out of new codes
+const GENERATE_DRAWS_PER_CODE: usize = 10;
+// How long an idempotency key stays pending when its pop never finishes, e.g. the instance died
+const IDEMPOTENCY_PENDING_SECONDS: u64 = 30;
+
+pub type UploadChunk = Result<Vec<String>, String>;
+pub type UploadSender = mpsc::Sender<UploadChunk>;
+
+#[derive(Clone)]
 pub struct CouponService {
     repo: CouponRepository,
-    cache: CouponCache,
+    upload_repo: UploadJobRepository,
+    store: Arc<dyn CouponStore>,
     metrics: Metrics,
-    lock: DistributedLock,
-    batch_config: BatchInsertConfig,
+    config: CouponServiceConfig,
+    sets: CouponSetMetadata,
 }
 
 impl CouponService {
     pub fn new(
         repo: CouponRepository,
-        cache: CouponCache,
+        upload_repo: UploadJobRepository,
+        store: Arc<dyn CouponStore>,
         metrics: Metrics,
-        lock: DistributedLock,
-        batch_config: BatchInsertConfig,
+        config: CouponServiceConfig,
     ) -> Self {
+        let sets = CouponSetMetadata::new(
+            repo.clone(),
+            Duration::from_secs(config.coupon_set.metadata_ttl_seconds),
+        );
+        sets.listen();
+
         Self {
             repo,
-            cache,
+            upload_repo,
+            store,
             metrics,
-            lock,
-            batch_config,
+            config,
+            sets,
         }
     }
 
-    #[tracing::instrument(skip(self, payload))]
-    pub async fn spawn_upload_job(&self, set_id: i64, payload: Vec<String>) {
-        // TODO time how long this takes, for fun
+    // The returned sender takes the codes in chunks, the job finishes once it is dropped. Sending an
+    // error fails the job, e.g. when the upload was cut off.
+    #[tracing::instrument(skip(self))]
+    pub async fn spawn_upload_job(
+        &self,
+        set_id: i64,
+        expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> ServiceResult<(UploadJob, UploadSender)> {
+        // Fail right away instead of on the background job when the set doesn't exist
+        self.sets.get(set_id).await?;
 
-        let repo = self.repo.clone();
-        let metrics = self.metrics.clone();
+        let job = self
+            .upload_repo
+            .create(set_id, UploadJobKind::Upload)
+            .await?;
+
+        // Small buffer so that a slow database slows down reading the upload instead of piling up
+        // chunks in memory
+        let (sender, mut receiver) = mpsc::channel::<UploadChunk>(UPLOAD_CHANNEL_SIZE);
+
+        let service = self.clone();
+        let job_id = job.id;
 
         tokio::task::spawn(async move {
-            let original = payload.len();
-            let mut coupons = Vec::with_capacity(payload.len());
+            service.metrics.job_upload.inc();
+
+            let mut counts = UploadJobCounts::default();
 
-            for coupon in payload.into_iter() {
-                if let Ok(id) = Uuid::parse_str(&coupon) {
-                    coupons.push(Coupon { id, set_id })
+            service
+                .update_upload_job(job_id, UploadJobState::Running, counts, None)
+                .await;
+
+            let mut result = Ok(());
+
+            while let Some(chunk) = receiver.recv().await {
+                result = match chunk {
+                    Ok(codes) => service
+                        .insert_upload_chunk(job_id, set_id, codes, expires_at, &mut counts)
+                        .await
+                        .map_err(|err| err.to_string()),
+                    Err(err) => Err(err),
+                };
+
+                if result.is_err() {
+                    break;
                 }
+
+                service
+                    .update_upload_job(job_id, UploadJobState::Running, counts, None)
+                    .await;
             }
 
-            let mapped = coupons.len();
+            // Stops the uploader from sending more chunks
+            drop(receiver);
 
-            if original != mapped {
-                tracing::warn!(
-                    set_id,
-                    diff = original - mapped,
-                    "could not map all coupons"
-                );
+            if counts.rejected > 0 {
+                tracing::warn!(set_id, diff = counts.rejected, "could not add all coupons");
+            }
+
+            match result {
+                Ok(()) => {
+                    tracing::info!(rows_affected = counts.inserted, set_id, "added coupons");
+
+                    service
+                        .update_upload_job(job_id, UploadJobState::Succeeded, counts, None)
+                        .await;
+                }
+                Err(err_str) => {
+                    tracing::error!(set_id, error = err_str, "failed to add coupons");
+
+                    service
+                        .update_upload_job(job_id, UploadJobState::Failed, counts, Some(err_str))
+                        .await;
+                }
             }
+        });
+
+        Ok((job, sender))
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn spawn_generate_job(
+        &self,
+        set_id: i64,
+        generate_dto: GenerateCouponsDto,
+    ) -> ServiceResult<UploadJob> {
+        let set = self.sets.get(set_id).await?;
+
+        if !generate_dto.generator.fits(&set.code_format) {
+            return Err(ServiceError::BadRequest(
+                "Generated codes would not match the code format of the set".to_string(),
+            ));
+        }
+
+        if generate_dto
+            .generator
+            .keyspace()
+            .is_some_and(|keyspace| generate_dto.count as u128 > keyspace)
+        {
+            return Err(ServiceError::BadRequest(
+                "The generator can't produce that many distinct codes".to_string(),
+            ));
+        }
+
+        let job = self
+            .upload_repo
+            .create(set_id, UploadJobKind::Generate)
+            .await?;
+
+        let service = self.clone();
+        let job_id = job.id;
+
+        tokio::task::spawn(async move {
+            service.metrics.job_generate.inc();
+
+            let mut counts = UploadJobCounts::default();
+
+            service
+                .update_upload_job(job_id, UploadJobState::Running, counts, None)
+                .await;
 
-            match repo.batch_insert(coupons).await {
-                Ok(rows_affected) => {
-                    metrics.batch_inserts.inc();
+            let result = service
+                .generate_coupons(job_id, set_id, generate_dto, &mut counts)
+                .await;
 
-                    tracing::info!(rows_affected, set_id, "added coupons");
+            match result {
+                Ok(()) => {
+                    tracing::info!(rows_affected = counts.inserted, set_id, "generated coupons");
+
+                    service
+                        .update_upload_job(job_id, UploadJobState::Succeeded, counts, None)
+                        .await;
                 }
                 Err(err) => {
                     let err_str = err.to_string();
 
-                    tracing::error!(set_id, error = err_str, "failed to add coupons");
+                    tracing::error!(set_id, error = err_str, "failed to generate coupons");
+
+                    service
+                        .update_upload_job(job_id, UploadJobState::Failed, counts, Some(err_str))
+                        .await;
                 }
             }
         });
+
+        Ok(job)
+    }
+
+    // Codes that already exist are skipped by the insert and generated again in the next chunk,
+    // until the count is reached or too many chunks in a row don't add anything. A chunk that
+    // can't draw enough distinct codes fails right away, the keyspace is nearly used up.
+    async fn generate_coupons(
+        &self,
+        job_id: Uuid,
+        set_id: i64,
+        generate_dto: GenerateCouponsDto,
+        counts: &mut UploadJobCounts,
+    ) -> ServiceResult<()> {
+        let mut remaining = generate_dto.count;
+        let mut attempts = 0;
+
+        while remaining > 0 {
+            let chunk_size = remaining.min(self.config.batch.insert_total) as usize;
+
+            let codes = {
+                let mut rng = rand::thread_rng();
+                let mut codes = HashSet::with_capacity(chunk_size);
+                let mut draws = 0;
+
+                while codes.len() < chunk_size {
+                    if draws >= chunk_size * GENERATE_DRAWS_PER_CODE {
+                        return Err(ServiceError::Conflict(
+                            anyhow!("no new codes left for the generator"),
+                            "code".to_string(),
+                        ));
+                    }
+
+                    codes.insert(generate_dto.generator.generate(&mut rng));
+                    draws += 1;
+                }
+
+                codes
+            };
+
+            let coupons = codes
+                .into_iter()
+                .map(|code| Coupon { code, set_id })
+                .collect::<Vec<Coupon>>();
+
+            let inserted = self
+                .repo
+                .batch_insert(coupons, job_id, generate_dto.expires_at)
+                .await? as i64;
+
+            self.metrics.batch_inserts.inc();
+
+            counts.parsed += chunk_size as i64;
+            counts.rejected += chunk_size as i64 - inserted;
+            counts.inserted += inserted;
+            remaining -= inserted;
+
+            attempts = if inserted == 0 { attempts + 1 } else { 0 };
+
+            if attempts >= GENERATE_MAX_ATTEMPTS {
+                return Err(ServiceError::Conflict(
+                    anyhow!("no new codes left for the generator"),
+                    "code".to_string(),
+                ));
+            }
+
+            self.update_upload_job(job_id, UploadJobState::Running, *counts, None)
+                .await;
+        }
+
+        Ok(())
+    }
+
+    pub fn upload_chunk_size(&self) -> usize {
+        self.config.batch.insert_total as usize
+    }
+
+    // Inserts the valid codes, the rest is recorded so that it shows up in the upload report
+    async fn insert_upload_chunk(
+        &self,
+        job_id: Uuid,
+        set_id: i64,
+        codes: Vec<String>,
+        expires_at: Option<chrono::DateTime<chrono::Utc>>,
+        counts: &mut UploadJobCounts,
+    ) -> ServiceResult<()> {
+        counts.parsed += codes.len() as i64;
+
+        let format = self.sets.get(set_id).await?.code_format;
+
+        let mut seen = HashSet::with_capacity(codes.len());
+        let mut rejections = vec![];
+        let mut coupons = Vec::with_capacity(codes.len());
+
+        for code in codes.into_iter() {
+            match format.normalize(&code) {
+                Some(normalized) if seen.insert(normalized.clone()) => coupons.push((
+                    code,
+                    Coupon {
+                        code: normalized,
+                        set_id,
+                    },
+                )),
+                Some(_) => rejections.push(UploadRejection {
+                    code,
+                    reason: UploadRejectionReason::Duplicate,
+                    existing_set_id: None,
+                }),
+                None => rejections.push(UploadRejection {
+                    code,
+                    reason: UploadRejectionReason::Invalid,
+                    existing_set_id: None,
+                }),
+            }
+        }
+
+        let normalized = coupons
+            .iter()
+            .map(|(_, coupon)| coupon.code.clone())
+            .collect::<Vec<String>>();
+
+        let existing = self
+            .repo
+            .existing_coupons(&normalized)
+            .await?
+            .into_iter()
+            .map(|coupon| (coupon.code.clone(), coupon))
+            .collect::<HashMap<String, ExistingCoupon>>();
+
+        let mut to_insert = Vec::with_capacity(coupons.len());
+
+        for (code, coupon) in coupons.into_iter() {
+            match existing.get(&coupon.code) {
+                // Added by an earlier chunk of the same upload
+                Some(found) if found.upload_job_id == Some(job_id) => {
+                    rejections.push(UploadRejection {
+                        code,
+                        reason: UploadRejectionReason::Duplicate,
+                        existing_set_id: None,
+                    })
+                }
+                Some(found) => rejections.push(UploadRejection {
+                    code,
+                    reason: UploadRejectionReason::Existing,
+                    existing_set_id: Some(found.set_id),
+                }),
+                None => to_insert.push(coupon),
+            }
+        }
+
+        counts.rejected += rejections.len() as i64;
+
+        if !rejections.is_empty() {
+            self.upload_repo.add_rejections(job_id, rejections).await?;
+        }
+
+        if !to_insert.is_empty() {
+            // Codes inserted concurrently by another upload are skipped by the conflict clause
+            let rows_affected = self
+                .repo
+                .batch_insert(to_insert, job_id, expires_at)
+                .await?;
+
+            self.metrics.batch_inserts.inc();
+
+            counts.inserted += rows_affected as i64;
+        }
+
+        Ok(())
+    }
+
+    async fn update_upload_job(
+        &self,
+        job_id: Uuid,
+        state: UploadJobState,
+        counts: UploadJobCounts,
+        error: Option<String>,
+    ) {
+        if let Err(err) = self.upload_repo.update(job_id, state, counts, error).await {
+            let err_str = err.to_string();
+
+            tracing::error!(error = err_str, "failed to update upload job");
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn get_upload_job(&self, job_id: Uuid) -> ServiceResult<UploadJob> {
+        let result = self.upload_repo.get(job_id).await?;
+        Ok(result)
     }
 
     #[tracing::instrument(skip(self))]
-    pub async fn pop_coupon(&self, set_id: i64) -> ServiceResult<Coupon> {
-        let mut cache = self.cache.clone();
+    pub async fn get_upload_report(&self, job_id: Uuid) -> ServiceResult<UploadReportResponseDto> {
+        let job = self.upload_repo.get(job_id).await?;
+        let rejections = self.upload_repo.rejections(job_id).await?;
+
+        let mut report = UploadReportResponseDto {
+            job,
+            invalid: vec![],
+            duplicate: vec![],
+            existing: vec![],
+        };
+
+        for rejection in rejections.into_iter() {
+            match (rejection.reason, rejection.existing_set_id) {
+                (UploadRejectionReason::Invalid, _) => report.invalid.push(rejection.code),
+                (UploadRejectionReason::Duplicate, _) => report.duplicate.push(rejection.code),
+                (UploadRejectionReason::Existing, set_id) => {
+                    report.existing.push(ExistingCouponDto {
+                        code: rejection.code,
+                        set_id: set_id.unwrap_or_default(),
+                    })
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    #[tracing::instrument(skip(self, principal), fields(subject = %principal.subject))]
+    pub async fn pop_coupon(&self, set_id: i64, principal: &Principal) -> ServiceResult<Coupon> {
+        // Sticky sets don't hand out coupons they assigned earlier either while unavailable
+        self.ensure_available(set_id).await?;
+
+        if self.sets.get(set_id).await?.sticky {
+            return self.pop_sticky_coupon(set_id, &principal.subject).await;
+        }
+
+        self.store.pop_coupon(set_id, &principal.subject).await
+    }
+
+    // Sticky sets hand out a single coupon per subject, later pops return that same coupon
+    async fn pop_sticky_coupon(&self, set_id: i64, subject: &str) -> ServiceResult<Coupon> {
+        if let Some(code) = self.store.assigned_coupon(set_id, subject).await? {
+            return Ok(Coupon { code, set_id });
+        }
+
+        match self.repo.claim_assignment(set_id, subject).await? {
+            IdempotencyClaim::New => {}
+            IdempotencyClaim::Pending => {
+                return Err(ServiceError::Conflict(
+                    anyhow!("a coupon is already being assigned to the same subject"),
+                    "coupon_assignment".to_string(),
+                ))
+            }
+            IdempotencyClaim::Replay(code) => {
+                self.store.assign_coupon(set_id, subject, &code).await?;
+
+                return Ok(Coupon { code, set_id });
+            }
+        }
 
-        let cached = cache.pop_coupon(set_id).await?;
+        match self.store.pop_coupon(set_id, subject).await {
+            Ok(coupon) => {
+                self.repo
+                    .store_assignment(set_id, subject, &coupon.code)
+                    .await?;
+                self.store
+                    .assign_coupon(set_id, subject, &coupon.code)
+                    .await?;
 
-        if let Some(cached) = cached {
-            self.metrics.cache_hit.inc();
+                Ok(coupon)
+            }
+            Err(err) => {
+                // Let the subject try again
+                self.repo.release_assignment(set_id, subject).await?;
+
+                Err(err)
+            }
+        }
+    }
 
-            return Ok(Coupon {
-                id: Uuid::try_parse(&cached)?,
+    #[tracing::instrument(skip(self, principal), fields(subject = %principal.subject))]
+    pub async fn pop_coupon_idempotent(
+        &self,
+        set_id: i64,
+        idempotency_key: &str,
+        principal: &Principal,
+    ) -> ServiceResult<Coupon> {
+        let window = self.config.idempotency.window_seconds;
+        let subject = &principal.subject;
+
+        // Keys are scoped to the subject, so a key reused by someone else never replays a coupon
+        // that was handed out to another subject
+        match self
+            .store
+            .claim_idempotency_key(
                 set_id,
-            });
+                subject,
+                idempotency_key,
+                IDEMPOTENCY_PENDING_SECONDS,
+            )
+            .await?
+        {
+            IdempotencyClaim::New => {}
+            IdempotencyClaim::Pending => {
+                return Err(ServiceError::Conflict(
+                    anyhow!("a request with the same idempotency key is in progress"),
+                    "idempotency_key".to_string(),
+                ))
+            }
+            IdempotencyClaim::Replay(cached) => {
+                self.metrics.idempotent_replays.inc();
+
+                return Ok(Coupon {
+                    code: cached,
+                    set_id,
+                });
+            }
+        }
+
+        match self.pop_coupon(set_id, principal).await {
+            Ok(coupon) => {
+                self.store
+                    .store_idempotency_key(set_id, subject, idempotency_key, &coupon.code, window)
+                    .await?;
+
+                Ok(coupon)
+            }
+            Err(err) => {
+                // Let the client retry with the same key
+                self.store
+                    .release_idempotency_key(set_id, subject, idempotency_key)
+                    .await?;
+
+                Err(err)
+            }
         }
+    }
+
+    // Claims up to `count` coupons, in chunks of at most `insert_total` coupons. The stream ends
+    // early when the set runs out of coupons.
+    pub fn claim_coupons(
+        &self,
+        set_id: i64,
+        count: i64,
+        principal: Principal,
+    ) -> impl Stream<Item = ServiceResult<Vec<Coupon>>> {
+        let chunk_size = self.config.batch.insert_total;
+
+        futures::stream::unfold(
+            (self.clone(), principal, count),
+            move |(service, principal, remaining)| async move {
+                if remaining <= 0 {
+                    return None;
+                }
+
+                let requested = remaining.min(chunk_size);
+
+                let result = service.claim_chunk(set_id, requested, &principal).await;
 
-        self.metrics.cache_miss.inc();
+                let remaining = match &result {
+                    Ok(coupons) if coupons.is_empty() => return None,
+                    Ok(coupons) if (coupons.len() as i64) == requested => remaining - requested,
+                    _ => 0,
+                };
 
-        if let Some(lock) = self
-            .lock
-            .lock(&format!("{}{}", self.batch_config.lock_prefix, set_id))
+                Some((result, (service, principal, remaining)))
+            },
+        )
+    }
+
+    #[tracing::instrument(skip(self, principal), fields(subject = %principal.subject))]
+    async fn claim_chunk(
+        &self,
+        set_id: i64,
+        count: i64,
+        principal: &Principal,
+    ) -> ServiceResult<Vec<Coupon>> {
+        self.ensure_available(set_id).await?;
+        self.ensure_not_sticky(set_id).await?;
+
+        self.store
+            .pop_coupons(set_id, count, &principal.subject)
             .await
-        {
-            let unlock = || async {
-                self.lock.unlock(lock).await;
-            };
+    }
+
+    #[tracing::instrument(skip(self, principal), fields(subject = %principal.subject))]
+    pub async fn reserve_coupon(
+        &self,
+        set_id: i64,
+        principal: &Principal,
+    ) -> ServiceResult<CouponReservation> {
+        self.ensure_available(set_id).await?;
+        self.ensure_not_sticky(set_id).await?;
+
+        self.store
+            .reserve_coupon(
+                set_id,
+                self.config.reservation.ttl_seconds,
+                &principal.subject,
+            )
+            .await
+    }
+
+    #[tracing::instrument(skip(self, principal), fields(subject = %principal.subject))]
+    pub async fn confirm_reservation(
+        &self,
+        reservation_id: Uuid,
+        principal: &Principal,
+    ) -> ServiceResult<Coupon> {
+        self.store
+            .confirm_reservation(reservation_id, &principal.subject)
+            .await
+    }
+
+    #[tracing::instrument(skip(self, principal), fields(subject = %principal.subject))]
+    pub async fn cancel_reservation(
+        &self,
+        reservation_id: Uuid,
+        principal: &Principal,
+    ) -> ServiceResult<Coupon> {
+        self.store
+            .cancel_reservation(reservation_id, &principal.subject)
+            .await
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn lookup_coupon(&self, code: &str) -> ServiceResult<CouponLookupResponseDto> {
+        let mut record = self.repo.get_coupon(code).await?;
+
+        let status = self.coupon_status(&mut record).await?;
+
+        Ok(Self::lookup_response(record, status))
+    }
+
+    // Retrying with the same idempotency key returns the earlier redemption, any other attempt on a
+    // redeemed coupon is a conflict
+    #[tracing::instrument(skip(self, principal), fields(subject = %principal.subject))]
+    pub async fn redeem_coupon(
+        &self,
+        code: &str,
+        idempotency_key: Option<&str>,
+        principal: &Principal,
+    ) -> ServiceResult<CouponLookupResponseDto> {
+        let mut record = self.repo.get_coupon(code).await?;
+
+        if record.redeemed_at.is_none() {
+            match self.coupon_status(&mut record).await? {
+                CouponStatus::HandedOut => {}
+                CouponStatus::Expired => {
+                    return Err(ServiceError::Gone("Coupon expired".to_string()))
+                }
+                _ => {
+                    return Err(ServiceError::Unavailable(
+                        "Coupon was not handed out".to_string(),
+                    ))
+                }
+            }
+
+            if record
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= Utc::now())
+            {
+                return Err(ServiceError::Gone("Coupon expired".to_string()));
+            }
 
-            let mut coupons = self
+            match self
                 .repo
-                .pop_coupons(set_id, self.batch_config.insert_total)
-                .await?;
+                .redeem_coupon(code, idempotency_key, record.issued_at, &principal.subject)
+                .await
+            {
+                Ok(record) => {
+                    self.metrics.coupons_redeemed.inc();
 
-            if coupons.is_empty() {
-                unlock().await;
-                return self.pop_from_cache(set_id).await;
+                    return Ok(Self::lookup_response(record, CouponStatus::Redeemed));
+                }
+                // Redeemed concurrently, decided below like any other redeemed coupon
+                Err(DatabaseError::NotFound) => {}
+                Err(err) => return Err(err.into()),
             }
+        }
 
-            let coupon = coupons.pop().ok_or(ServiceError::NotFound)?;
+        let record = self.repo.get_coupon(code).await?;
 
-            cache.batch_insert(set_id, &coupons).await?;
+        match (idempotency_key, record.redemption_key.as_deref()) {
+            (Some(key), Some(redemption_key)) if key == redemption_key => {
+                self.metrics.idempotent_replays.inc();
 
-            unlock().await;
+                Ok(Self::lookup_response(record, CouponStatus::Redeemed))
+            }
+            _ => Err(ServiceError::Conflict(
+                anyhow!("coupon was already redeemed"),
+                "redeemed_at".to_string(),
+            )),
+        }
+    }
 
-            return Ok(Coupon {
-                id: coupon.id,
-                set_id: coupon.set_id,
-            });
+    fn lookup_response(record: CouponRecord, status: CouponStatus) -> CouponLookupResponseDto {
+        CouponLookupResponseDto {
+            code: record.code,
+            set_id: record.set_id,
+            status,
+            created_at: record.created_at,
+            expires_at: record.expires_at,
+            reserved_until: record.reserved_until,
+            issued_at: record.issued_at,
+            redeemed_at: record.redeemed_at,
         }
+    }
 
-        // Retry since some other thread might be inserting coupons at the same time
-        let coupon = Retry::spawn(
-            ExponentialBackoff::from_millis(100).map(jitter).take(3),
-            || async { self.pop_from_cache(set_id).await },
-        )
-        .await?;
+    // `used` only says that the coupon left the database, the store tells whether it was handed out
+    // or is still waiting in the cache. Coupons the store handed out get its issue time.
+    async fn coupon_status(&self, record: &mut CouponRecord) -> ServiceResult<CouponStatus> {
+        if record.redeemed_at.is_some() {
+            return Ok(CouponStatus::Redeemed);
+        }
 
-        Ok(coupon)
+        if record.issued_at.is_some() {
+            return Ok(CouponStatus::HandedOut);
+        }
+
+        if record.reservation_id.is_some() {
+            return Ok(CouponStatus::Reserved);
+        }
+
+        if record.used {
+            if let Some(issue) = self
+                .store
+                .issued_coupon(record.set_id, &record.code)
+                .await?
+            {
+                record.issued_at = issue.issued_at;
+
+                return Ok(CouponStatus::HandedOut);
+            }
+        }
+
+        if record
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+        {
+            return Ok(CouponStatus::Expired);
+        }
+
+        if record.used {
+            Ok(CouponStatus::InCache)
+        } else {
+            Ok(CouponStatus::Available)
+        }
     }
 
-    async fn pop_from_cache(&self, set_id: i64) -> ServiceResult<Coupon> {
-        let mut cache = self.cache.clone();
+    // Refuses to hand out coupons from sets that are not active or are outside of their campaign
+    // window, without touching the cache
+    async fn ensure_available(&self, set_id: i64) -> ServiceResult<()> {
+        let set = self.sets.get(set_id).await?;
+        let now = Utc::now();
+
+        match set.status {
+            CouponSetStatus::Active => {}
+            CouponSetStatus::Paused => {
+                return Err(ServiceError::Unavailable(
+                    "Coupon set is paused".to_string(),
+                ))
+            }
+            CouponSetStatus::Archived => {
+                return Err(ServiceError::Gone("Coupon set is archived".to_string()))
+            }
+        }
+
+        if set.starts_at.is_some_and(|starts_at| now < starts_at) {
+            return Err(ServiceError::Unavailable(
+                "Coupon set campaign has not started yet".to_string(),
+            ));
+        }
 
-        let cached = cache.pop_coupon(set_id).await?;
+        if set.ends_at.is_some_and(|ends_at| now >= ends_at) {
+            return Err(ServiceError::Gone(
+                "Coupon set campaign has ended".to_string(),
+            ));
+        }
 
-        match cached {
-            Some(cached) => Ok(Coupon {
-                id: Uuid::try_parse(&cached)?,
-                set_id,
-            }),
-            None => Err(ServiceError::NotFound),
+        Ok(())
+    }
+
+    // Claims and reservations would get around the one coupon per subject of sticky sets
+    async fn ensure_not_sticky(&self, set_id: i64) -> ServiceResult<()> {
+        if self.sets.get(set_id).await?.sticky {
+            return Err(ServiceError::BadRequest(
+                "Coupon set hands out one coupon per subject, use pop instead".to_string(),
+            ));
         }
+
+        Ok(())
     }
 
     #[tracing::instrument(skip(self))]
@@ -160,14 +820,76 @@ impl CouponService {
         create_dto: CreateCouponSetDto,
     ) -> ServiceResult<CouponSet> {
         let result = self.repo.create_set(create_dto).await?;
+
+        self.store.register_set(result.id).await?;
+
+        Ok(result)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn update_set_status(
+        &self,
+        set_id: i64,
+        status: CouponSetStatus,
+    ) -> ServiceResult<CouponSet> {
+        let current = self.repo.get_set(set_id).await?;
+
+        if current.status == CouponSetStatus::Archived && status != CouponSetStatus::Archived {
+            return Err(ServiceError::Unavailable(
+                "Coupon set is archived".to_string(),
+            ));
+        }
+
+        let result = self.repo.update_set_status(set_id, status).await?;
+
+        self.sets.invalidate(set_id);
+
+        if status == CouponSetStatus::Archived {
+            self.drain_set(set_id).await?;
+        }
+
         Ok(result)
     }
 
+    #[tracing::instrument(skip(self))]
+    pub async fn delete_set(&self, set_id: i64) -> ServiceResult<()> {
+        // Archive first so that no more coupons are handed out while the set is being drained
+        self.update_set_status(set_id, CouponSetStatus::Archived)
+            .await?;
+
+        self.repo.delete_set(set_id).await?;
+
+        self.sets.invalidate(set_id);
+
+        Ok(())
+    }
+
+    async fn drain_set(&self, set_id: i64) -> ServiceResult<()> {
+        self.store.drain_set(set_id).await?;
+
+        let mut total = 0;
+
+        loop {
+            let deleted = self
+                .repo
+                .delete_set_coupons(set_id, self.config.batch.insert_total)
+                .await?;
+
+            if deleted == 0 {
+                break;
+            }
+
+            total += deleted;
+        }
+
+        tracing::info!(total, "coupon set drained");
+
+        Ok(())
+    }
+
     #[tracing::instrument(skip_all)]
     pub async fn set_status(&self) -> ServiceResult<Vec<CouponStatusResponseDto>> {
-        let mut cache = self.cache.clone();
-
-        let cache = cache.set_status().await?;
+        let cache = self.store.set_status().await?;
         let database = self.repo.set_status().await?;
 
         let mut result = vec![];
@@ -185,8 +907,11 @@ impl CouponService {
                 id,
                 name: status.name,
                 created_at: status.created_at,
+                status: status.status,
                 total_cache: in_cache,
                 total_database: status.total_coupons,
+                total_issued: status.total_issued,
+                total_redeemed: status.total_redeemed,
             });
         }
 
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::database::coupon::CouponRepository;
+use crate::error::service::ServiceResult;
+use crate::model::coupon::CouponSet;
+
+const LISTEN_RETRY_SECONDS: u64 = 5;
+
+// In-memory copy of the coupon sets, so that checking a set before popping doesn't need a round
+// trip to the database. Status changes and deletes made by any instance are broadcast by the
+// database, `ttl` only bounds how long a copy lives when a broadcast is missed.
+#[derive(Clone)]
+pub struct CouponSetMetadata {
+    repo: CouponRepository,
+    ttl: Duration,
+    entries: Arc<RwLock<HashMap<i64, (Instant, CouponSet)>>>,
+}
+
+impl CouponSetMetadata {
+    pub fn new(repo: CouponRepository, ttl: Duration) -> Self {
+        Self {
+            repo,
+            ttl,
+            entries: Default::default(),
+        }
+    }
+
+    pub async fn get(&self, set_id: i64) -> ServiceResult<CouponSet> {
+        {
+            let entries = self.entries.read().expect("metadata lock PoisonError");
+
+            if let Some((fetched_at, set)) = entries.get(&set_id) {
+                if fetched_at.elapsed() < self.ttl {
+                    return Ok(set.clone());
+                }
+            }
+        }
+
+        let set = self.repo.get_set(set_id).await?;
+
+        self.entries
+            .write()
+            .expect("metadata lock PoisonError")
+            .insert(set_id, (Instant::now(), set.clone()));
+
+        Ok(set)
+    }
+
+    pub fn invalidate(&self, set_id: i64) {
+        self.entries
+            .write()
+            .expect("metadata lock PoisonError")
+            .remove(&set_id);
+    }
+
+    fn clear(&self) {
+        self.entries
+            .write()
+            .expect("metadata lock PoisonError")
+            .clear();
+    }
+
+    // Drops the copies of sets that other instances changed, see the `coupon_set_changed` trigger
+    pub fn listen(&self) {
+        let metadata = self.clone();
+
+        tokio::task::spawn(async move {
+            metadata.listen_worker().await;
+        });
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn listen_worker(&self) {
+        loop {
+            let mut listener = match self.repo.listen_set_changes().await {
+                Ok(listener) => listener,
+                Err(error) => {
+                    let error = error.to_string();
+                    tracing::error!(error, "error listening for coupon set changes");
+                    tokio::time::sleep(Duration::from_secs(LISTEN_RETRY_SECONDS)).await;
+                    continue;
+                }
+            };
+
+            // Changes made while nobody was listening were missed
+            self.clear();
+
+            loop {
+                match listener.try_recv().await {
+                    Ok(Some(notification)) => match notification.payload().parse() {
+                        Ok(set_id) => self.invalidate(set_id),
+                        Err(_) => self.clear(),
+                    },
+                    // The connection was lost, the next call reconnects
+                    Ok(None) => self.clear(),
+                    Err(error) => {
+                        let error = error.to_string();
+                        tracing::error!(error, "error receiving coupon set changes");
+                        tokio::time::sleep(Duration::from_secs(LISTEN_RETRY_SECONDS)).await;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -2,6 +2,7 @@ use anyhow::Context;
 use serde::Deserialize;
 
 pub mod coupon;
+pub mod metadata;
 pub mod userlogin;
 
 #[derive(Deserialize, Clone)]
@@ -17,3 +18,98 @@ impl BatchInsertConfig {
         envy::from_env().context("Failed to get env vars")
     }
 }
+
+#[derive(Deserialize, Clone)]
+pub struct ReservationConfig {
+    #[serde(rename(deserialize = "reservation_ttl_seconds"))]
+    pub ttl_seconds: i64,
+}
+
+impl ReservationConfig {
+    pub fn new() -> anyhow::Result<Self> {
+        envy::from_env().context("Failed to get env vars")
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct IdempotencyConfig {
+    #[serde(rename(deserialize = "idempotency_window_seconds"))]
+    pub window_seconds: u64,
+}
+
+impl IdempotencyConfig {
+    pub fn new() -> anyhow::Result<Self> {
+        envy::from_env().context("Failed to get env vars")
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CouponSetConfig {
+    #[serde(rename(deserialize = "coupon_set_metadata_ttl_seconds"))]
+    pub metadata_ttl_seconds: u64,
+}
+
+impl CouponSetConfig {
+    pub fn new() -> anyhow::Result<Self> {
+        envy::from_env().context("Failed to get env vars")
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CacheBreakerConfig {
+    #[serde(rename(deserialize = "cache_breaker_failure_threshold"))]
+    pub failure_threshold: u32,
+    #[serde(rename(deserialize = "cache_breaker_open_seconds"))]
+    pub open_seconds: u64,
+}
+
+impl CacheBreakerConfig {
+    pub fn new() -> anyhow::Result<Self> {
+        envy::from_env().context("Failed to get env vars")
+    }
+}
+
+#[derive(Clone)]
+pub struct CouponServiceConfig {
+    pub batch: BatchInsertConfig,
+    pub reservation: ReservationConfig,
+    pub idempotency: IdempotencyConfig,
+    pub coupon_set: CouponSetConfig,
+    pub breaker: CacheBreakerConfig,
+}
+
+impl CouponServiceConfig {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            batch: BatchInsertConfig::new()?,
+            reservation: ReservationConfig::new()?,
+            idempotency: IdempotencyConfig::new()?,
+            coupon_set: CouponSetConfig::new()?,
+            breaker: CacheBreakerConfig::new()?,
+        })
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ClaimConfig {
+    #[serde(rename(deserialize = "claim_max_count"))]
+    pub max_count: i64,
+}
+
+impl ClaimConfig {
+    pub fn new() -> anyhow::Result<Self> {
+        envy::from_env().context("Failed to get env vars")
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct GenerateConfig {
+    #[serde(rename(deserialize = "generate_max_count"))]
+    pub max_count: i64,
+}
+
+impl GenerateConfig {
+    pub fn new() -> anyhow::Result<Self> {
+        envy::from_env().context("Failed to get env vars")
+    }
+}
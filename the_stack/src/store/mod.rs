@@ -0,0 +1,120 @@
+pub mod postgres;
+pub mod redis;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::error::service::ServiceResult;
+use crate::model::coupon::Coupon;
+use crate::model::coupon::CouponIssue;
+use crate::model::coupon::CouponReservation;
+use crate::model::coupon::CouponSetCacheStatus;
+use crate::model::coupon::IdempotencyClaim;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CouponStoreBackend {
+    // Coupons are handed out from redis lists that are refilled from postgres
+    Redis,
+    // Coupons are handed out straight from postgres, no redis needed
+    Postgres,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CouponStoreConfig {
+    #[serde(rename(deserialize = "coupon_store_backend"))]
+    pub backend: CouponStoreBackend,
+}
+
+impl CouponStoreConfig {
+    pub fn new() -> anyhow::Result<Self> {
+        envy::from_env().context("Failed to get env vars")
+    }
+}
+
+// How coupons are handed out, reserved and tracked until they are archived. Everything else,
+// uploads, lookups and redemptions, goes through the database in both backends.
+#[async_trait]
+pub trait CouponStore: Send + Sync {
+    // `issued_to` ends up in the archive along with the coupon
+    async fn pop_coupon(&self, set_id: i64, issued_to: &str) -> ServiceResult<Coupon>;
+
+    // Returns fewer coupons than `count` when the set runs out
+    async fn pop_coupons(
+        &self,
+        set_id: i64,
+        count: i64,
+        issued_to: &str,
+    ) -> ServiceResult<Vec<Coupon>>;
+
+    // Only `reserved_by` can confirm or cancel the reservation
+    async fn reserve_coupon(
+        &self,
+        set_id: i64,
+        ttl_seconds: i64,
+        reserved_by: &str,
+    ) -> ServiceResult<CouponReservation>;
+
+    async fn confirm_reservation(
+        &self,
+        reservation_id: Uuid,
+        issued_to: &str,
+    ) -> ServiceResult<Coupon>;
+
+    async fn cancel_reservation(
+        &self,
+        reservation_id: Uuid,
+        reserved_by: &str,
+    ) -> ServiceResult<Coupon>;
+
+    // Puts coupons whose reservation expired back where they can be handed out again. Their
+    // reservations are only cleared afterwards, so this is called again for the same coupons
+    // when clearing fails.
+    async fn return_coupons(&self, set_id: i64, coupons: &[Coupon]) -> ServiceResult<()>;
+
+    // A key that is claimed but not stored yet counts as pending for `pending_seconds`, so that a
+    // pop that never finishes doesn't block its key for the whole window
+    async fn claim_idempotency_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+        pending_seconds: u64,
+    ) -> ServiceResult<IdempotencyClaim>;
+
+    async fn store_idempotency_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+        code: &str,
+        window_seconds: u64,
+    ) -> ServiceResult<()>;
+
+    async fn release_idempotency_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+    ) -> ServiceResult<()>;
+
+    // Fast lookup of the coupon a sticky set handed out to a subject. The assignment table is the
+    // source of truth, so None only means that the store doesn't know.
+    async fn assigned_coupon(&self, set_id: i64, subject: &str) -> ServiceResult<Option<String>>;
+
+    async fn assign_coupon(&self, set_id: i64, subject: &str, code: &str) -> ServiceResult<()>;
+
+    // Who a coupon that left the database was handed out to and when, None while it's waiting to be
+    async fn issued_coupon(&self, set_id: i64, code: &str) -> ServiceResult<Option<CouponIssue>>;
+
+    async fn register_set(&self, set_id: i64) -> ServiceResult<()>;
+
+    // Forgets everything the store keeps for a set outside of the coupon table, after archiving the
+    // coupons it handed out
+    async fn drain_set(&self, set_id: i64) -> ServiceResult<()>;
+
+    // Coupons waiting outside of the database, per set
+    async fn set_status(&self) -> ServiceResult<Vec<CouponSetCacheStatus>>;
+}
@@ -0,0 +1,166 @@
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::database::coupon::CouponRepository;
+use crate::error::service::ServiceError;
+use crate::error::service::ServiceResult;
+use crate::model::coupon::Coupon;
+use crate::model::coupon::CouponIssue;
+use crate::model::coupon::CouponReservation;
+use crate::model::coupon::CouponSetCacheStatus;
+use crate::model::coupon::IdempotencyClaim;
+use crate::store::CouponStore;
+
+// Hands coupons out with `FOR UPDATE SKIP LOCKED`, moving them to the archive in the same
+// statement, so there is nothing to clean up afterwards
+#[derive(Clone)]
+pub struct PostgresCouponStore {
+    repo: CouponRepository,
+}
+
+impl PostgresCouponStore {
+    pub fn new(repo: CouponRepository) -> Self {
+        Self { repo }
+    }
+}
+
+#[async_trait]
+impl CouponStore for PostgresCouponStore {
+    async fn pop_coupon(&self, set_id: i64, issued_to: &str) -> ServiceResult<Coupon> {
+        self.repo
+            .hand_out_coupons(set_id, 1, issued_to)
+            .await?
+            .pop()
+            .ok_or(ServiceError::NotFound)
+    }
+
+    async fn pop_coupons(
+        &self,
+        set_id: i64,
+        count: i64,
+        issued_to: &str,
+    ) -> ServiceResult<Vec<Coupon>> {
+        let result = self.repo.hand_out_coupons(set_id, count, issued_to).await?;
+
+        Ok(result)
+    }
+
+    async fn reserve_coupon(
+        &self,
+        set_id: i64,
+        ttl_seconds: i64,
+        reserved_by: &str,
+    ) -> ServiceResult<CouponReservation> {
+        let result = self
+            .repo
+            .reserve_next_coupon(set_id, ttl_seconds, reserved_by)
+            .await?;
+
+        Ok(result)
+    }
+
+    async fn confirm_reservation(
+        &self,
+        reservation_id: Uuid,
+        issued_to: &str,
+    ) -> ServiceResult<Coupon> {
+        let result = self
+            .repo
+            .hand_out_reservation(reservation_id, issued_to)
+            .await?;
+
+        Ok(result)
+    }
+
+    async fn cancel_reservation(
+        &self,
+        reservation_id: Uuid,
+        reserved_by: &str,
+    ) -> ServiceResult<Coupon> {
+        let result = self
+            .repo
+            .release_reservation(reservation_id, reserved_by)
+            .await?;
+
+        Ok(result)
+    }
+
+    async fn return_coupons(&self, _set_id: i64, coupons: &[Coupon]) -> ServiceResult<()> {
+        let codes = coupons
+            .iter()
+            .map(|coupon| coupon.code.clone())
+            .collect::<Vec<String>>();
+
+        self.repo.release_coupons(&codes).await?;
+
+        Ok(())
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+        pending_seconds: u64,
+    ) -> ServiceResult<IdempotencyClaim> {
+        let result = self
+            .repo
+            .claim_idempotency_key(set_id, subject, key, pending_seconds)
+            .await?;
+
+        Ok(result)
+    }
+
+    async fn store_idempotency_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+        code: &str,
+        window_seconds: u64,
+    ) -> ServiceResult<()> {
+        self.repo
+            .store_idempotency_key(set_id, subject, key, code, window_seconds)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn release_idempotency_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+    ) -> ServiceResult<()> {
+        self.repo
+            .release_idempotency_key(set_id, subject, key)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn assigned_coupon(&self, _set_id: i64, _subject: &str) -> ServiceResult<Option<String>> {
+        Ok(None)
+    }
+
+    async fn assign_coupon(&self, _set_id: i64, _subject: &str, _code: &str) -> ServiceResult<()> {
+        Ok(())
+    }
+
+    // Handed out coupons are archived right away, the ones still in the coupon table are not
+    async fn issued_coupon(&self, _set_id: i64, _code: &str) -> ServiceResult<Option<CouponIssue>> {
+        Ok(None)
+    }
+
+    async fn register_set(&self, _set_id: i64) -> ServiceResult<()> {
+        Ok(())
+    }
+
+    async fn drain_set(&self, _set_id: i64) -> ServiceResult<()> {
+        Ok(())
+    }
+
+    async fn set_status(&self) -> ServiceResult<Vec<CouponSetCacheStatus>> {
+        Ok(vec![])
+    }
+}
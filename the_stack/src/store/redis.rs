@@ -0,0 +1,526 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::cache::breaker::CircuitBreaker;
+use crate::cache::coupon::CouponCache;
+use crate::cache::lock::DistributedLock;
+use crate::cache::lock::REFILL_LOCK_TTL_MILLIS;
+use crate::database::coupon::CouponRepository;
+use crate::error::service::ServiceError;
+use crate::error::service::ServiceResult;
+use crate::metrics::Metrics;
+use crate::model::coupon::Coupon;
+use crate::model::coupon::CouponIssue;
+use crate::model::coupon::CouponReservation;
+use crate::model::coupon::CouponSet;
+use crate::model::coupon::CouponSetCacheStatus;
+use crate::model::coupon::IdempotencyClaim;
+use crate::service::CouponServiceConfig;
+use crate::store::CouponStore;
+
+// Hands coupons out from redis lists that are refilled from postgres in batches. Handed out
+// coupons wait in the used list until the cleanup job archives them.
+#[derive(Clone)]
+pub struct RedisCouponStore {
+    repo: CouponRepository,
+    cache: CouponCache,
+    metrics: Metrics,
+    lock: DistributedLock,
+    config: CouponServiceConfig,
+    breaker: CircuitBreaker,
+}
+
+impl RedisCouponStore {
+    pub fn new(
+        repo: CouponRepository,
+        cache: CouponCache,
+        metrics: Metrics,
+        lock: DistributedLock,
+        config: CouponServiceConfig,
+    ) -> Self {
+        let breaker = CircuitBreaker::new(
+            config.breaker.failure_threshold,
+            Duration::from_secs(config.breaker.open_seconds),
+            metrics.cache_breaker_state.clone(),
+        );
+
+        Self {
+            repo,
+            cache,
+            metrics,
+            lock,
+            config,
+            breaker,
+        }
+    }
+
+    // Pops a coupon and records it in the destination list, either the used or the reserved one.
+    // Reserved coupons are only issued once the reservation is confirmed.
+    async fn take_coupon(
+        &self,
+        set_id: i64,
+        destination: &str,
+        issued_to: Option<&str>,
+    ) -> ServiceResult<Coupon> {
+        let mut cache = self.cache.clone();
+
+        let popped = cache.pop_coupon(set_id, destination, issued_to).await;
+        self.breaker.record(&popped);
+        let popped = popped?;
+
+        if popped.refill {
+            let store = self.clone();
+            tokio::task::spawn(async move {
+                store.refill(set_id).await;
+            });
+        }
+
+        if let Some(cached) = popped.coupon {
+            self.metrics.cache_hit.inc();
+
+            return Ok(Coupon {
+                code: cached,
+                set_id,
+            });
+        }
+
+        self.metrics.cache_miss.inc();
+
+        // Don't wait for the cache to be refilled, rows being moved to the cache are skipped
+        let coupon = self
+            .repo
+            .pop_coupons(set_id, 1)
+            .await?
+            .pop()
+            .ok_or(ServiceError::NotFound)?;
+
+        self.record_taken(
+            &mut cache,
+            destination,
+            std::slice::from_ref(&coupon),
+            issued_to,
+        )
+        .await?;
+
+        Ok(coupon)
+    }
+
+    // Records coupons that were taken from the database on a cache miss in the destination list.
+    // When the cache can't take them, handed out coupons are archived right away like the breaker
+    // fallback does, and reserved ones go back to the database.
+    async fn record_taken(
+        &self,
+        cache: &mut CouponCache,
+        destination: &str,
+        coupons: &[Coupon],
+        issued_to: Option<&str>,
+    ) -> ServiceResult<()> {
+        let pushed = cache.push_coupons(destination, coupons, issued_to).await;
+        self.breaker.record(&pushed);
+
+        let Err(err) = pushed else {
+            return Ok(());
+        };
+
+        let err_str = err.to_string();
+
+        let Some(issued_to) = issued_to else {
+            tracing::error!(
+                error = err_str,
+                "failed to record reserved coupons in the cache"
+            );
+
+            self.release(coupons).await;
+
+            return Err(err.into());
+        };
+
+        tracing::warn!(
+            error = err_str,
+            "failed to record handed out coupons in the cache, archiving them"
+        );
+
+        let codes = coupons
+            .iter()
+            .map(|coupon| coupon.code.clone())
+            .collect::<Vec<String>>();
+        let issued = vec![Some(CouponIssue::new(issued_to)); codes.len()];
+
+        if let Err(err) = self.repo.archive_coupons(&codes, &issued).await {
+            self.release(coupons).await;
+
+            return Err(err.into());
+        }
+
+        self.metrics.cache_bypassed.inc_by(coupons.len() as f64);
+
+        Ok(())
+    }
+
+    // Used while the cache circuit breaker is open
+    async fn hand_out_coupons(
+        &self,
+        set_id: i64,
+        count: i64,
+        issued_to: &str,
+    ) -> ServiceResult<Vec<Coupon>> {
+        let coupons = self.repo.hand_out_coupons(set_id, count, issued_to).await?;
+
+        self.metrics.cache_bypassed.inc_by(coupons.len() as f64);
+
+        Ok(coupons)
+    }
+
+    // Puts coupons that were taken from the database but never made it to the cache back
+    async fn release(&self, coupons: &[Coupon]) {
+        let codes = coupons
+            .iter()
+            .map(|coupon| coupon.code.clone())
+            .collect::<Vec<String>>();
+
+        if let Err(err) = self.repo.release_coupons(&codes).await {
+            let err_str = err.to_string();
+
+            tracing::error!(error = err_str, "failed to release coupons");
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn refill(&self, set_id: i64) {
+        // Same lock as the filler job
+        let Some(lock) = self
+            .lock
+            .lock_for(
+                &format!("{}{}", self.config.batch.lock_prefix, set_id),
+                REFILL_LOCK_TTL_MILLIS,
+            )
+            .await
+        else {
+            tracing::info!("coupon set is already being refilled");
+            return;
+        };
+
+        let result = self.refill_from_database(set_id).await;
+
+        self.lock.unlock(lock).await;
+
+        match result {
+            Ok(total) => tracing::info!(total, "coupon set refilled"),
+            Err(err) => {
+                let err_str = err.to_string();
+
+                tracing::error!(error = err_str, "failed to refill coupon set");
+            }
+        }
+    }
+
+    async fn refill_from_database(&self, set_id: i64) -> ServiceResult<usize> {
+        let mut cache = self.cache.clone();
+
+        let coupons = self
+            .repo
+            .pop_coupons(set_id, self.config.batch.insert_total)
+            .await?;
+
+        if let Err(err) = cache.batch_insert(set_id, &coupons).await {
+            // Marked as used above, they would be in neither the database nor the cache
+            self.release(&coupons).await;
+
+            return Err(err.into());
+        }
+
+        // Keep the marker when the database is exhausted so that it isn't queried on every miss
+        if !coupons.is_empty() {
+            cache.clear_refill(set_id).await?;
+        }
+
+        Ok(coupons.len())
+    }
+}
+
+#[async_trait]
+impl CouponStore for RedisCouponStore {
+    async fn pop_coupon(&self, set_id: i64, issued_to: &str) -> ServiceResult<Coupon> {
+        if !self.breaker.allow() {
+            return self
+                .hand_out_coupons(set_id, 1, issued_to)
+                .await?
+                .pop()
+                .ok_or(ServiceError::NotFound);
+        }
+
+        self.take_coupon(set_id, &CouponSet::used_key(set_id), Some(issued_to))
+            .await
+    }
+
+    async fn pop_coupons(
+        &self,
+        set_id: i64,
+        count: i64,
+        issued_to: &str,
+    ) -> ServiceResult<Vec<Coupon>> {
+        if !self.breaker.allow() {
+            return self.hand_out_coupons(set_id, count, issued_to).await;
+        }
+
+        let mut cache = self.cache.clone();
+        let used_key = CouponSet::used_key(set_id);
+
+        let popped = cache
+            .pop_coupons(set_id, count, &used_key, Some(issued_to))
+            .await;
+        self.breaker.record(&popped);
+
+        let mut result = popped?
+            .into_iter()
+            .map(|cached| Coupon {
+                code: cached,
+                set_id,
+            })
+            .collect::<Vec<Coupon>>();
+
+        self.metrics.cache_hit.inc_by(result.len() as f64);
+
+        let missing = count - result.len() as i64;
+
+        if missing > 0 {
+            let coupons = self.repo.pop_coupons(set_id, missing).await?;
+
+            self.metrics.cache_miss.inc_by(coupons.len() as f64);
+
+            self.record_taken(&mut cache, &used_key, &coupons, Some(issued_to))
+                .await?;
+
+            result.extend(coupons);
+        }
+
+        Ok(result)
+    }
+
+    async fn reserve_coupon(
+        &self,
+        set_id: i64,
+        ttl_seconds: i64,
+        reserved_by: &str,
+    ) -> ServiceResult<CouponReservation> {
+        if self.breaker.is_open() {
+            return Err(ServiceError::Degraded(
+                "reservations are not available while the cache is down".to_string(),
+            ));
+        }
+
+        let reserved_key = CouponSet::reserved_key(set_id);
+
+        let coupon = self.take_coupon(set_id, &reserved_key, None).await?;
+
+        match self
+            .repo
+            .reserve_coupon(&coupon.code, ttl_seconds, reserved_by)
+            .await
+        {
+            Ok(reservation) => Ok(reservation),
+            Err(err) => {
+                // Give the coupon back instead of losing it
+                let mut cache = self.cache.clone();
+                cache.return_coupons(set_id, &[coupon]).await?;
+
+                Err(err.into())
+            }
+        }
+    }
+
+    async fn confirm_reservation(
+        &self,
+        reservation_id: Uuid,
+        issued_to: &str,
+    ) -> ServiceResult<Coupon> {
+        let coupon = self
+            .repo
+            .confirm_reservation(reservation_id, issued_to)
+            .await?;
+
+        let mut cache = self.cache.clone();
+        cache
+            .move_coupons(
+                &CouponSet::reserved_key(coupon.set_id),
+                &CouponSet::used_key(coupon.set_id),
+                std::slice::from_ref(&coupon),
+                Some(issued_to),
+            )
+            .await?;
+
+        Ok(coupon)
+    }
+
+    async fn cancel_reservation(
+        &self,
+        reservation_id: Uuid,
+        reserved_by: &str,
+    ) -> ServiceResult<Coupon> {
+        let coupon = self
+            .repo
+            .cancel_reservation(reservation_id, reserved_by)
+            .await?;
+
+        let mut cache = self.cache.clone();
+        cache
+            .return_coupons(coupon.set_id, std::slice::from_ref(&coupon))
+            .await?;
+
+        Ok(coupon)
+    }
+
+    async fn return_coupons(&self, set_id: i64, coupons: &[Coupon]) -> ServiceResult<()> {
+        let mut cache = self.cache.clone();
+        let returned = cache.return_coupons(set_id, coupons).await?;
+
+        if returned < coupons.len() as i64 {
+            tracing::warn!(
+                set_id,
+                returned,
+                total = coupons.len(),
+                "some expired reservations were already returned"
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn claim_idempotency_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+        pending_seconds: u64,
+    ) -> ServiceResult<IdempotencyClaim> {
+        // Keys live in redis, handing out a coupon without them could hand out a second one on retry
+        if self.breaker.is_open() {
+            return Err(ServiceError::Degraded(
+                "idempotent pops are not available while the cache is down".to_string(),
+            ));
+        }
+
+        let mut cache = self.cache.clone();
+        let result = cache
+            .claim_idempotency_key(set_id, subject, key, pending_seconds)
+            .await?;
+
+        Ok(result)
+    }
+
+    async fn store_idempotency_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+        code: &str,
+        window_seconds: u64,
+    ) -> ServiceResult<()> {
+        let mut cache = self.cache.clone();
+        cache
+            .store_idempotency_key(set_id, subject, key, code, window_seconds)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn release_idempotency_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+    ) -> ServiceResult<()> {
+        let mut cache = self.cache.clone();
+        cache.release_idempotency_key(set_id, subject, key).await?;
+
+        Ok(())
+    }
+
+    async fn assigned_coupon(&self, set_id: i64, subject: &str) -> ServiceResult<Option<String>> {
+        if self.breaker.is_open() {
+            return Ok(None);
+        }
+
+        let mut cache = self.cache.clone();
+        let result = cache.assigned_coupon(set_id, subject).await;
+        self.breaker.record(&result);
+
+        // The assignment table is checked next, a failed lookup doesn't need to fail the pop
+        Ok(result.unwrap_or_default())
+    }
+
+    async fn assign_coupon(&self, set_id: i64, subject: &str, code: &str) -> ServiceResult<()> {
+        if self.breaker.is_open() {
+            return Ok(());
+        }
+
+        let mut cache = self.cache.clone();
+        let result = cache.assign_coupon(set_id, subject, code).await;
+        self.breaker.record(&result);
+
+        if let Err(error) = result {
+            let error = error.to_string();
+            tracing::warn!(set_id, error, "failed to cache coupon assignment");
+        }
+
+        Ok(())
+    }
+
+    // `used` only says that the coupon left the database, the issued hash tells whether it was
+    // handed out or is still waiting in the cache
+    async fn issued_coupon(&self, set_id: i64, code: &str) -> ServiceResult<Option<CouponIssue>> {
+        if self.breaker.allow() {
+            let mut cache = self.cache.clone();
+            let result = cache.issued_coupon(set_id, code).await;
+            self.breaker.record(&result);
+
+            if let Ok(result) = result {
+                return Ok(result);
+            }
+        }
+
+        // The cleanup may have archived the coupon in the meantime, otherwise only the cache knows
+        match self.repo.archived_coupon_issue(code).await? {
+            Some(issue) => Ok(Some(issue)),
+            None => Err(ServiceError::Degraded(
+                "whether the coupon was handed out is not known while the cache is down"
+                    .to_string(),
+            )),
+        }
+    }
+
+    async fn register_set(&self, set_id: i64) -> ServiceResult<()> {
+        let mut cache = self.cache.clone();
+        cache.register_set(set_id).await?;
+
+        Ok(())
+    }
+
+    // Handed out coupons are archived the way the cleanup worker would, so that draining the set
+    // doesn't lose who got them
+    async fn drain_set(&self, set_id: i64) -> ServiceResult<()> {
+        let mut cache = self.cache.clone();
+
+        let codes = cache.handed_out_coupons(set_id).await?;
+
+        for chunk in codes.chunks(self.config.batch.insert_total as usize) {
+            let issued = cache.issued_coupons(set_id, chunk).await?;
+            let rows_affected = self.repo.archive_coupons(chunk, &issued).await?;
+
+            self.metrics.archived_coupons.inc_by(rows_affected as f64);
+        }
+
+        cache.drain_set(set_id).await?;
+
+        Ok(())
+    }
+
+    async fn set_status(&self) -> ServiceResult<Vec<CouponSetCacheStatus>> {
+        let mut cache = self.cache.clone();
+        let result = cache.set_status().await?;
+
+        Ok(result)
+    }
+}
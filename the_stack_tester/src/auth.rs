@@ -34,6 +34,7 @@ struct Credentials {
 pub struct CredentialsManager {
     kc: Credentials,
     kc_endpoint: Url,
+    kc_client_id: String,
 }
 
 impl CredentialsManager {
@@ -41,9 +42,19 @@ impl CredentialsManager {
         let kc_endpoint = Url::from_str(&config.kc_auth_endpoint)
             .expect("Could not parse kc_auth_endpoint to URL");
 
-        let kc = Self::kc_login(&kc_endpoint, &config.username, &config.password).await?;
-
-        Ok(Self { kc, kc_endpoint })
+        let kc = Self::kc_login(
+            &kc_endpoint,
+            &config.kc_client_id,
+            &config.username,
+            &config.password,
+        )
+        .await?;
+
+        Ok(Self {
+            kc,
+            kc_endpoint,
+            kc_client_id: config.kc_client_id.clone(),
+        })
     }
 
     pub async fn kc_token(&mut self) -> anyhow::Result<String> {
@@ -58,7 +69,13 @@ impl CredentialsManager {
             return Ok(self.kc.access_token.clone());
         }
 
-        self.kc = Self::kc_login(&self.kc_endpoint, &self.kc.username, &self.kc.password).await?;
+        self.kc = Self::kc_login(
+            &self.kc_endpoint,
+            &self.kc_client_id,
+            &self.kc.username,
+            &self.kc.password,
+        )
+        .await?;
 
         Ok(self.kc.access_token.clone())
     }
@@ -68,7 +85,12 @@ impl CredentialsManager {
         todo!() // TODO: I might implement this..
     }
 
-    async fn kc_login(url: &Url, username: &str, password: &str) -> anyhow::Result<Credentials> {
+    async fn kc_login(
+        url: &Url,
+        client_id: &str,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<Credentials> {
         let client = reqwest::Client::new();
 
         let mut headers = HeaderMap::new();
@@ -81,8 +103,8 @@ impl CredentialsManager {
             .post(url.clone())
             .headers(headers)
             .body(format!(
-                "username={}&password={}&client_id=admin-cli&grant_type=password",
-                username, password
+                "username={}&password={}&client_id={}&grant_type=password",
+                username, password, client_id
             ))
             .send()
             .await?
@@ -64,7 +64,7 @@ async fn fetch_all(
             continue;
         };
 
-        coupons.remove(&coupon.id.to_string());
+        coupons.remove(&coupon.code);
 
         let rem = coupons.len();
 
@@ -0,0 +1,117 @@
+use std::net::Ipv4Addr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::Router;
+use reqwest::Url;
+use the_stack::auth::jwks::JwksCache;
+use the_stack::auth::jwks::JwksConfig;
+use tokio::task::JoinSet;
+
+use crate::TesterConfig;
+
+const STUB_KID: &str = "stub";
+const STUB_REFETCH_SECONDS: u64 = 5;
+// Keeps the fetch open long enough for the other lookups to pile up behind it
+const STUB_DELAY_MILLISECONDS: u64 = 200;
+
+// Only the key id is looked at, the key itself is never used to verify anything
+static STUB_JWKS: &str = r#"{"keys": [{"kty": "RSA", "kid": "stub", "use": "sig", "alg": "RS256",
+    "n": "u1SU1LfVLPHCozMxH2Mo4lgOEePzNm0tRgeLezV6ffAt0gunVTLw7onLRnrq0_IzW7yWR7QkrmBL7jTKEn5u-qKhbwKfBstIs-bMY2Zkp18gnTxKLxoS2tFczGkPLPgizskuemMghRniWaoLcyehkd3qqGElvW_VDL5AaWTg0nLVkjRo9z-40RQzuVaE8AkAFmxZzow3x-VJYKdjykkJ0iT9wCS0DRTXu269V264Vf_3jvredZiKRkgwlL9xNAwxXFg0x_XFw005UWVRIkdgcKWTjpBP2dPwVZ4WWC-9aGVd-Gyn1o0CLelf4rEjGoXbAAEgAqeGUxrcIlbjXfbcmw",
+    "e": "AQAB"}]}"#;
+
+// Many concurrent lookups against a cold cache have to result in a single fetch, and tokens with an
+// unknown key id can only force another one once the refetch interval has passed
+#[tracing::instrument(skip_all)]
+pub async fn run_jwks(config: TesterConfig) -> anyhow::Result<()> {
+    let fetches = Arc::new(AtomicUsize::new(0));
+
+    let app = Router::new()
+        .route("/certs", axum::routing::get(stub_certs))
+        .with_state(fetches.clone());
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let endpoint = Url::parse(&format!("http://{}/certs", listener.local_addr()?))?;
+
+    tokio::task::spawn(async move {
+        if let Err(err) = axum::serve(listener, app).await {
+            tracing::error!("{:#}", err);
+        }
+    });
+
+    let metrics = the_stack::metrics::setup("tester")?;
+    let cache = JwksCache::new(
+        endpoint,
+        JwksConfig {
+            ttl_seconds: 300,
+            refetch_seconds: STUB_REFETCH_SECONDS,
+        },
+        metrics,
+    );
+
+    let lookups = config.stress_clients * 32;
+
+    let found = find_concurrently(&cache, STUB_KID, lookups).await?;
+    anyhow::ensure!(
+        found == lookups,
+        "{} of {} lookups found the key",
+        found,
+        lookups
+    );
+    expect_fetches(&fetches, 1)?;
+
+    let found = find_concurrently(&cache, "unknown", lookups).await?;
+    anyhow::ensure!(found == 0, "{} lookups found an unknown key", found);
+    expect_fetches(&fetches, 1)?;
+
+    tokio::time::sleep(Duration::from_secs(STUB_REFETCH_SECONDS) + Duration::from_millis(100))
+        .await;
+
+    find_concurrently(&cache, "unknown", lookups).await?;
+    expect_fetches(&fetches, 2)?;
+
+    tracing::info!(lookups, "jwks was fetched once per refetch interval");
+
+    Ok(())
+}
+
+async fn stub_certs(State(fetches): State<Arc<AtomicUsize>>) -> &'static str {
+    fetches.fetch_add(1, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_millis(STUB_DELAY_MILLISECONDS)).await;
+
+    STUB_JWKS
+}
+
+async fn find_concurrently(cache: &JwksCache, kid: &str, lookups: usize) -> anyhow::Result<usize> {
+    let mut js = JoinSet::new();
+
+    for _ in 0..lookups {
+        let cache = cache.clone();
+        let kid = kid.to_string();
+        js.spawn(async move { cache.find(&kid).await });
+    }
+
+    let mut found = 0;
+
+    while let Some(result) = js.join_next().await {
+        if result??.is_some() {
+            found += 1;
+        }
+    }
+
+    Ok(found)
+}
+
+fn expect_fetches(fetches: &AtomicUsize, expected: usize) -> anyhow::Result<()> {
+    let fetched = fetches.load(Ordering::SeqCst);
+    anyhow::ensure!(
+        fetched == expected,
+        "expected {} jwks fetches, got {}",
+        expected,
+        fetched
+    );
+
+    Ok(())
+}
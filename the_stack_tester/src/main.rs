@@ -1,21 +1,28 @@
 pub mod auth;
 pub mod bench;
 pub mod fetch;
+pub mod jwks;
 pub mod runner;
+pub mod store;
+pub mod stress;
 pub mod upload;
 
 use std::net::Ipv4Addr;
 use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::Context;
 use auth::CredentialsManager;
 use serde::Deserialize;
 use serde::Serialize;
+use the_stack::model::upload::UploadJobState;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::Layer;
 
 use crate::upload::create_set;
+use crate::upload::get_upload_job;
+use crate::upload::is_finished;
 use crate::upload::upload_coupons;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -24,6 +31,14 @@ pub enum TesterMode {
     Benchmark,
     #[serde(rename(deserialize = "simulation"))]
     Simulation,
+    #[serde(rename(deserialize = "stress"))]
+    Stress,
+    // Runs against a local stub JWKS server instead of the service
+    #[serde(rename(deserialize = "jwks"))]
+    Jwks,
+    // Runs the same scenarios against the postgres and the redis coupon store, without the service
+    #[serde(rename(deserialize = "store"))]
+    Store,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -40,10 +55,14 @@ pub struct TesterConfig {
     pub password: String,
     #[serde(rename(deserialize = "tester_kc_auth_endpoint"))]
     pub kc_auth_endpoint: String,
+    #[serde(rename(deserialize = "tester_kc_client_id"))]
+    pub kc_client_id: String,
     #[serde(rename(deserialize = "tester_timeout_milliseconds"))]
     pub timeout: u64,
     #[serde(rename(deserialize = "tester_mode"))]
     pub mode: TesterMode,
+    #[serde(rename(deserialize = "tester_stress_clients"))]
+    pub stress_clients: usize,
 }
 
 #[tokio::main]
@@ -67,8 +86,17 @@ async fn main() -> anyhow::Result<()> {
 
     tracing::info!("Running in {:?} mode", config.mode);
 
+    if let TesterMode::Jwks = config.mode {
+        return jwks::run_jwks(config).await;
+    }
+
+    if let TesterMode::Store = config.mode {
+        return store::run_store().await;
+    }
+
     let client = reqwest::Client::new();
     let mut sets = vec![];
+    let mut jobs = vec![];
 
     let mut cred_manager = CredentialsManager::new(&config).await?;
 
@@ -80,7 +108,7 @@ async fn main() -> anyhow::Result<()> {
         )
         .await?;
 
-        let coupons = upload_coupons(
+        let (job, coupons) = upload_coupons(
             &client,
             &cred_manager.kc_token().await?,
             set.id,
@@ -90,18 +118,49 @@ async fn main() -> anyhow::Result<()> {
 
         tracing::info!("Uploaded {} coupons to set {}", coupons.len(), set.id);
 
+        jobs.push(job);
         sets.push((set, coupons));
     }
 
     tracing::info!(
-        "Waiting {} seconds for data to be inserted into the database",
+        "Waiting up to {} seconds for data to be inserted into the database",
         config.wait_secs
     );
-    tokio::time::sleep(Duration::from_secs(config.wait_secs)).await;
+
+    let deadline = Instant::now() + Duration::from_secs(config.wait_secs);
+
+    for job in jobs.into_iter() {
+        let mut job = job;
+
+        while !is_finished(&job) {
+            if Instant::now() >= deadline {
+                anyhow::bail!("Upload job {} did not finish in time", job.id);
+            }
+
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            job = get_upload_job(&client, &cred_manager.kc_token().await?, job.id).await?;
+        }
+
+        match job.state {
+            UploadJobState::Failed => {
+                anyhow::bail!("Upload job {} failed: {:?}", job.id, job.error)
+            }
+            _ => tracing::info!(
+                "Upload job {} inserted {} of {} coupons",
+                job.id,
+                job.inserted,
+                job.parsed
+            ),
+        }
+    }
 
     match config.mode {
         TesterMode::Benchmark => bench::run_benchmark(config, sets, cred_manager.clone()).await?,
         TesterMode::Simulation => runner::simulation(config, sets, cred_manager).await?,
+        TesterMode::Stress => stress::run_stress(config, sets, cred_manager).await?,
+        TesterMode::Jwks | TesterMode::Store => {
+            unreachable!("handled before the sets are created")
+        }
     }
 
     Ok(())
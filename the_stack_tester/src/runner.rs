@@ -122,7 +122,7 @@ async fn fetch_all(
             };
 
         gotten += 1;
-        let coupon_id = coupon.id.to_string();
+        let coupon_id = coupon.code.clone();
 
         result
             .entry(selected_id)
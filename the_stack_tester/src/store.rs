@@ -0,0 +1,443 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use the_stack::api::dto::CreateCouponSetDto;
+use the_stack::cache::coupon::CouponCache;
+use the_stack::database::coupon::CouponRepository;
+use the_stack::database::upload::UploadJobRepository;
+use the_stack::error::service::ServiceError;
+use the_stack::error::service::ServiceResult;
+use the_stack::model::coupon::Coupon;
+use the_stack::model::coupon::CouponReservation;
+use the_stack::model::coupon::IdempotencyClaim;
+use the_stack::model::upload::UploadJobKind;
+use the_stack::service::CouponServiceConfig;
+use the_stack::store::postgres::PostgresCouponStore;
+use the_stack::store::redis::RedisCouponStore;
+use the_stack::store::CouponStore;
+use uuid::Uuid;
+
+// A refill can hold the rows of a set for a moment, between taking them from the database and
+// pushing them to the cache. Running out is only believed once it lasts this many attempts.
+const SETTLE_ATTEMPTS: usize = 5;
+const SETTLE_MILLISECONDS: u64 = 100;
+
+struct Suite<'a> {
+    store: &'a dyn CouponStore,
+    repo: &'a CouponRepository,
+    upload_repo: &'a UploadJobRepository,
+}
+
+// Runs the same scenarios against both store backends, straight on postgres and redis instead of
+// through the API, so that switching backends doesn't change what callers see
+#[tracing::instrument(skip_all)]
+pub async fn run_store() -> anyhow::Result<()> {
+    let db = the_stack::database::setup("tester").await?;
+    let (cache, lock) = the_stack::cache::setup("tester").await?;
+    let metrics = the_stack::metrics::setup("tester")?;
+    let coupon_config = CouponServiceConfig::new()?;
+
+    let repo = CouponRepository::new(db.clone());
+    let upload_repo = UploadJobRepository::new(db);
+
+    let postgres = PostgresCouponStore::new(repo.clone());
+    let redis = RedisCouponStore::new(
+        repo.clone(),
+        CouponCache::new(cache),
+        metrics,
+        lock,
+        coupon_config,
+    );
+
+    let backends: [(&str, &dyn CouponStore); 2] = [("postgres", &postgres), ("redis", &redis)];
+
+    for (backend, store) in backends.into_iter() {
+        let suite = Suite {
+            store,
+            repo: &repo,
+            upload_repo: &upload_repo,
+        };
+
+        suite
+            .run()
+            .await
+            .with_context(|| format!("{} store", backend))?;
+
+        tracing::info!(backend, "store passed every scenario");
+    }
+
+    Ok(())
+}
+
+impl Suite<'_> {
+    async fn run(&self) -> anyhow::Result<()> {
+        self.pops_hand_out_every_coupon_once()
+            .await
+            .context("pops")?;
+        self.bulk_pops_stop_at_the_end_of_the_set()
+            .await
+            .context("bulk pops")?;
+        self.reservations_belong_to_their_subject()
+            .await
+            .context("reservations")?;
+        self.expired_reservations_are_returned()
+            .await
+            .context("expired reservations")?;
+        self.idempotency_keys_replay_their_coupon()
+            .await
+            .context("idempotency keys")?;
+
+        Ok(())
+    }
+
+    async fn pops_hand_out_every_coupon_once(&self) -> anyhow::Result<()> {
+        let (set_id, codes) = self.create_set(5).await?;
+
+        let mut popped = HashSet::new();
+
+        for _ in 0..codes.len() {
+            let coupon = self
+                .pop(set_id, "alice")
+                .await?
+                .ok_or(anyhow!("set ran out early"))?;
+            anyhow::ensure!(
+                popped.insert(coupon.code.clone()),
+                "{} was handed out twice",
+                coupon.code
+            );
+        }
+
+        anyhow::ensure!(popped == codes, "handed out coupons from another set");
+        anyhow::ensure!(
+            self.pop(set_id, "alice").await?.is_none(),
+            "handed out more coupons than the set has"
+        );
+
+        Ok(())
+    }
+
+    async fn bulk_pops_stop_at_the_end_of_the_set(&self) -> anyhow::Result<()> {
+        let (set_id, codes) = self.create_set(5).await?;
+
+        let mut popped = HashSet::new();
+
+        loop {
+            let coupons = self.pop_many(set_id, 3).await?;
+
+            anyhow::ensure!(coupons.len() <= 3, "{} coupons for 3", coupons.len());
+
+            if coupons.is_empty() {
+                break;
+            }
+
+            for coupon in coupons.into_iter() {
+                anyhow::ensure!(
+                    popped.insert(coupon.code.clone()),
+                    "{} was handed out twice",
+                    coupon.code
+                );
+            }
+        }
+
+        anyhow::ensure!(
+            popped == codes,
+            "didn't hand out exactly the coupons of the set"
+        );
+
+        Ok(())
+    }
+
+    async fn reservations_belong_to_their_subject(&self) -> anyhow::Result<()> {
+        let (set_id, codes) = self.create_set(2).await?;
+
+        let reservation = self.reserve(set_id, 60, "alice").await?;
+        anyhow::ensure!(
+            codes.contains(&reservation.code),
+            "reserved a coupon of another set"
+        );
+
+        expect_not_found(
+            self.store
+                .cancel_reservation(reservation.reservation_id, "bob")
+                .await,
+        )
+        .context("cancelled by another subject")?;
+        expect_not_found(
+            self.store
+                .confirm_reservation(reservation.reservation_id, "bob")
+                .await,
+        )
+        .context("confirmed by another subject")?;
+
+        let confirmed = check(
+            self.store
+                .confirm_reservation(reservation.reservation_id, "alice")
+                .await,
+        )?;
+        anyhow::ensure!(
+            confirmed.code == reservation.code,
+            "confirmed another coupon"
+        );
+
+        expect_not_found(
+            self.store
+                .confirm_reservation(reservation.reservation_id, "alice")
+                .await,
+        )
+        .context("confirmed twice")?;
+
+        let reservation = self.reserve(set_id, 60, "alice").await?;
+        let cancelled = check(
+            self.store
+                .cancel_reservation(reservation.reservation_id, "alice")
+                .await,
+        )?;
+        anyhow::ensure!(
+            cancelled.code == reservation.code,
+            "cancelled another coupon"
+        );
+
+        let coupon = self
+            .pop(set_id, "alice")
+            .await?
+            .ok_or(anyhow!("cancelled coupon was not handed out again"))?;
+        anyhow::ensure!(
+            coupon.code == cancelled.code,
+            "handed out a confirmed coupon"
+        );
+        anyhow::ensure!(
+            self.pop(set_id, "alice").await?.is_none(),
+            "handed out more coupons than the set has"
+        );
+
+        Ok(())
+    }
+
+    // Same steps as the sweeper
+    async fn expired_reservations_are_returned(&self) -> anyhow::Result<()> {
+        let (set_id, _) = self.create_set(1).await?;
+
+        let reservation = self.reserve(set_id, 1, "alice").await?;
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        expect_not_found(
+            self.store
+                .confirm_reservation(reservation.reservation_id, "alice")
+                .await,
+        )
+        .context("confirmed after it expired")?;
+
+        let expired = self
+            .repo
+            .expired_reservations(i64::MAX)
+            .await
+            .map_err(|err| anyhow!("{}", err))?;
+        anyhow::ensure!(
+            expired
+                .iter()
+                .any(|expired| expired.reservation_id == reservation.reservation_id),
+            "reservation did not expire"
+        );
+
+        let coupon = Coupon {
+            code: reservation.code.clone(),
+            set_id,
+        };
+        check(
+            self.store
+                .return_coupons(set_id, std::slice::from_ref(&coupon))
+                .await,
+        )?;
+        self.repo
+            .clear_reservations(&[reservation.reservation_id])
+            .await
+            .map_err(|err| anyhow!("{}", err))?;
+
+        let popped = self
+            .pop(set_id, "bob")
+            .await?
+            .ok_or(anyhow!("expired coupon was not handed out again"))?;
+        anyhow::ensure!(popped.code == coupon.code, "handed out another coupon");
+
+        Ok(())
+    }
+
+    async fn idempotency_keys_replay_their_coupon(&self) -> anyhow::Result<()> {
+        let (set_id, _) = self.create_set(0).await?;
+
+        let key = Uuid::new_v4().to_string();
+
+        let claim = self.claim_key(set_id, "alice", &key).await?;
+        anyhow::ensure!(
+            matches!(claim, IdempotencyClaim::New),
+            "new key was not new"
+        );
+
+        let claim = self.claim_key(set_id, "alice", &key).await?;
+        anyhow::ensure!(
+            matches!(claim, IdempotencyClaim::Pending),
+            "claimed key was not pending"
+        );
+
+        check(
+            self.store
+                .store_idempotency_key(set_id, "alice", &key, "code", 60)
+                .await,
+        )?;
+
+        let claim = self.claim_key(set_id, "alice", &key).await?;
+        anyhow::ensure!(
+            matches!(claim, IdempotencyClaim::Replay(ref code) if code == "code"),
+            "stored key did not replay its coupon"
+        );
+
+        let claim = self.claim_key(set_id, "bob", &key).await?;
+        anyhow::ensure!(
+            matches!(claim, IdempotencyClaim::New),
+            "key of another subject was not new"
+        );
+
+        let key = Uuid::new_v4().to_string();
+
+        self.claim_key(set_id, "alice", &key).await?;
+        check(
+            self.store
+                .release_idempotency_key(set_id, "alice", &key)
+                .await,
+        )?;
+
+        let claim = self.claim_key(set_id, "alice", &key).await?;
+        anyhow::ensure!(
+            matches!(claim, IdempotencyClaim::New),
+            "released key was not new"
+        );
+
+        Ok(())
+    }
+
+    async fn create_set(&self, total: usize) -> anyhow::Result<(i64, HashSet<String>)> {
+        let set = self
+            .repo
+            .create_set(CreateCouponSetDto {
+                name: format!("Store suite {}", Uuid::new_v4()),
+                starts_at: None,
+                ends_at: None,
+                code_format: Default::default(),
+                sticky: false,
+            })
+            .await
+            .map_err(|err| anyhow!("{}", err))?;
+
+        check(self.store.register_set(set.id).await)?;
+
+        let job = self
+            .upload_repo
+            .create(set.id, UploadJobKind::Upload)
+            .await
+            .map_err(|err| anyhow!("{}", err))?;
+
+        let coupons = (0..total)
+            .map(|_| Coupon {
+                code: Uuid::new_v4().to_string(),
+                set_id: set.id,
+            })
+            .collect::<Vec<Coupon>>();
+        let codes = coupons.iter().map(|coupon| coupon.code.clone()).collect();
+
+        self.repo
+            .batch_insert(coupons, job.id, None)
+            .await
+            .map_err(|err| anyhow!("{}", err))?;
+
+        Ok((set.id, codes))
+    }
+
+    async fn claim_key(
+        &self,
+        set_id: i64,
+        subject: &str,
+        key: &str,
+    ) -> anyhow::Result<IdempotencyClaim> {
+        check(
+            self.store
+                .claim_idempotency_key(set_id, subject, key, 30)
+                .await,
+        )
+    }
+
+    async fn pop(&self, set_id: i64, subject: &str) -> anyhow::Result<Option<Coupon>> {
+        settled(|| async {
+            match self.store.pop_coupon(set_id, subject).await {
+                Ok(coupon) => Ok(Some(coupon)),
+                Err(ServiceError::NotFound) => Ok(None),
+                Err(err) => Err(anyhow!("{}", err)),
+            }
+        })
+        .await
+    }
+
+    async fn pop_many(&self, set_id: i64, count: i64) -> anyhow::Result<Vec<Coupon>> {
+        let coupons = settled(|| async {
+            let coupons = check(self.store.pop_coupons(set_id, count, "alice").await)?;
+
+            Ok((!coupons.is_empty()).then_some(coupons))
+        })
+        .await?;
+
+        Ok(coupons.unwrap_or_default())
+    }
+
+    async fn reserve(
+        &self,
+        set_id: i64,
+        ttl_seconds: i64,
+        reserved_by: &str,
+    ) -> anyhow::Result<CouponReservation> {
+        settled(|| async {
+            match self
+                .store
+                .reserve_coupon(set_id, ttl_seconds, reserved_by)
+                .await
+            {
+                Ok(reservation) => Ok(Some(reservation)),
+                Err(ServiceError::NotFound) => Ok(None),
+                Err(err) => Err(anyhow!("{}", err)),
+            }
+        })
+        .await?
+        .ok_or(anyhow!("set ran out of coupons to reserve"))
+    }
+}
+
+// Retries attempts that found nothing, see `SETTLE_ATTEMPTS`
+async fn settled<T, F, Fut>(attempt: F) -> anyhow::Result<Option<T>>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = anyhow::Result<Option<T>>>,
+{
+    for _ in 1..SETTLE_ATTEMPTS {
+        if let Some(result) = attempt().await? {
+            return Ok(Some(result));
+        }
+
+        tokio::time::sleep(Duration::from_millis(SETTLE_MILLISECONDS)).await;
+    }
+
+    attempt().await
+}
+
+fn check<T>(result: ServiceResult<T>) -> anyhow::Result<T> {
+    result.map_err(|err| anyhow!("{}", err))
+}
+
+fn expect_not_found<T>(result: ServiceResult<T>) -> anyhow::Result<()> {
+    match result {
+        Err(ServiceError::NotFound) => Ok(()),
+        Err(err) => Err(anyhow!("expected not found, got {}", err)),
+        Ok(_) => Err(anyhow!("expected not found, but it succeeded")),
+    }
+}
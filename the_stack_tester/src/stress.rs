@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use reqwest::StatusCode;
+use the_stack::model::coupon::CouponSet;
+use tokio::task::JoinSet;
+
+use crate::auth::CredentialsManager;
+use crate::fetch::fetch_coupon;
+use crate::fetch::FetchResult;
+use crate::TesterConfig;
+
+// Many concurrent clients draining the same sets, checking that no coupon is handed out twice
+#[tracing::instrument(skip_all)]
+pub async fn run_stress(
+    config: TesterConfig,
+    sets: Vec<(CouponSet, Vec<String>)>,
+    cred_manager: CredentialsManager,
+) -> anyhow::Result<()> {
+    let mut js = JoinSet::new();
+
+    for (set, _) in sets.iter() {
+        for _ in 0..config.stress_clients {
+            let set_id = set.id;
+            let cred_manager = cred_manager.clone();
+            js.spawn(async move { fetch_until_exhausted(set_id, cred_manager).await });
+        }
+    }
+
+    tracing::info!(
+        "Started {} clients for {} sets",
+        js.len(),
+        config.total_sets
+    );
+
+    let mut handed_out: HashMap<String, usize> = HashMap::new();
+
+    while let Some(result) = js.join_next().await {
+        match result {
+            Ok(ret) => match ret {
+                Ok(coupons) => {
+                    for coupon in coupons.into_iter() {
+                        *handed_out.entry(coupon).or_default() += 1;
+                    }
+                }
+                Err(err) => tracing::error!("{:#}", err),
+            },
+            Err(err) => tracing::error!("{:#}", err),
+        }
+    }
+
+    let duplicates = handed_out.values().filter(|total| **total > 1).count();
+
+    let missing = sets
+        .iter()
+        .flat_map(|(_, coupons)| coupons.iter())
+        .filter(|coupon| !handed_out.contains_key(*coupon))
+        .count();
+
+    tracing::info!(
+        handed_out = handed_out.len(),
+        duplicates,
+        missing,
+        "Stress test finished"
+    );
+
+    if duplicates > 0 {
+        return Err(anyhow!(
+            "{} coupons were handed out more than once",
+            duplicates
+        ));
+    }
+
+    if missing > 0 {
+        return Err(anyhow!("{} coupons were never handed out", missing));
+    }
+
+    tracing::info!("SUCCESS! No coupon was handed out twice!");
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(cred_manager))]
+async fn fetch_until_exhausted(
+    set_id: i64,
+    mut cred_manager: CredentialsManager,
+) -> anyhow::Result<Vec<String>> {
+    let client = reqwest::Client::new();
+    let mut result = vec![];
+
+    loop {
+        match fetch_coupon(&client, set_id, &cred_manager.kc_token().await?).await? {
+            FetchResult::Coupon(coupon) => result.push(coupon.code),
+            FetchResult::StatusError(StatusCode::NOT_FOUND) => break,
+            FetchResult::StatusError(status) => {
+                return Err(anyhow!("Status code error on set {}: {}", set_id, status))
+            }
+        }
+    }
+
+    Ok(result)
+}
@@ -5,6 +5,8 @@ use reqwest::Client;
 use reqwest::Url;
 use the_stack::api::dto::CreateCouponSetDto;
 use the_stack::model::coupon::CouponSet;
+use the_stack::model::upload::UploadJob;
+use the_stack::model::upload::UploadJobState;
 use uuid::Uuid;
 
 struct IdGenerator;
@@ -20,7 +22,13 @@ impl Iterator for IdGenerator {
 pub async fn create_set(client: &Client, token: &str, name: String) -> anyhow::Result<CouponSet> {
     let url = Url::from_str("http://localhost:3000/coupon_set")?;
 
-    let payload = CreateCouponSetDto { name: name.clone() };
+    let payload = CreateCouponSetDto {
+        name: name.clone(),
+        starts_at: None,
+        ends_at: None,
+        code_format: Default::default(),
+        sticky: false,
+    };
 
     let result = client
         .post(url)
@@ -41,7 +49,7 @@ pub async fn upload_coupons(
     token: &str,
     set_id: i64,
     total_coupons: usize,
-) -> anyhow::Result<Vec<String>> {
+) -> anyhow::Result<(UploadJob, Vec<String>)> {
     let url = Url::from_str(&format!(
         "http://localhost:3000/coupon_set/{}/upload",
         set_id
@@ -52,13 +60,43 @@ pub async fn upload_coupons(
         .map(|id| id.to_string())
         .collect::<Vec<String>>();
 
-    client
+    let job = client
         .post(url)
         .bearer_auth(token)
         .json(&coupons)
         .send()
         .await
-        .context("failed to upload coupons")?;
+        .context("failed to upload coupons")?
+        .error_for_status()?
+        .json::<UploadJob>()
+        .await?;
+
+    Ok((job, coupons))
+}
+
+pub async fn get_upload_job(
+    client: &Client,
+    token: &str,
+    job_id: Uuid,
+) -> anyhow::Result<UploadJob> {
+    let url = Url::from_str(&format!("http://localhost:3000/upload_job/{}", job_id))?;
+
+    let result = client
+        .get(url)
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("Failed to get upload job {}", job_id))?
+        .json::<UploadJob>()
+        .await?;
+
+    Ok(result)
+}
 
-    Ok(coupons)
+pub fn is_finished(job: &UploadJob) -> bool {
+    matches!(
+        job.state,
+        UploadJobState::Succeeded | UploadJobState::Failed
+    )
 }